@@ -0,0 +1,148 @@
+use axum::http::header::COOKIE;
+use axum::http::{HeaderName, Request};
+use serde::Deserialize;
+use crate::forwarding::{client_ip, ForwardingConf};
+
+/// Part of a request that is hashed to pick a server
+///
+/// Written in the config as `client_ip`, `header:<name>`, `cookie:<name>`,
+/// `path_prefix:<segments>` or `query:<param>`
+#[derive(Deserialize)]
+#[derive(Debug, Clone, PartialEq)]
+#[serde(try_from = "String")]
+pub enum KeySource {
+    /// Address of the client, as passed on by a trusted proxy or of the peer otherwise
+    ClientIp,
+    /// Value of a request header
+    Header(HeaderName),
+    /// Value of a cookie
+    Cookie(String),
+    /// The first `n` segments of the path, `path_prefix:1` maps `/neo/feed` to `/neo`
+    PathPrefix(usize),
+    /// Value of a query parameter
+    Query(String),
+}
+
+impl TryFrom<String> for KeySource {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        let (kind, arg) = match value.split_once(':') {
+            Some((kind, arg)) => (kind, Some(arg.trim())),
+            None => (value.as_str(), None),
+        };
+        match (kind, arg) {
+            ("client_ip", None) => Ok(KeySource::ClientIp),
+            ("header", Some(name)) if !name.is_empty() => HeaderName::try_from(name)
+                .map(KeySource::Header)
+                .map_err(|e| format!("invalid header name `{name}`: {e}")),
+            ("cookie", Some(name)) if !name.is_empty() => Ok(KeySource::Cookie(name.to_string())),
+            ("path_prefix", Some(n)) => n.parse()
+                .map(KeySource::PathPrefix)
+                .map_err(|_| format!("expected a number of path segments, got `{n}`")),
+            ("query", Some(name)) if !name.is_empty() => Ok(KeySource::Query(name.to_string())),
+            _ => Err(format!("unknown key source `{value}`, expected one of client_ip, \
+                header:<name>, cookie:<name>, path_prefix:<segments> or query:<param>")),
+        }
+    }
+}
+
+/// Request affinity configuration
+#[derive(Deserialize)]
+#[derive(Debug, Clone, Default, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct AffinityConf {
+    /// Sources combined into the request key, an empty list routes randomly
+    #[serde(default)]
+    pub key: Vec<KeySource>,
+}
+
+impl KeySource {
+    fn extract<B>(&self, forwarding: &ForwardingConf, req: &Request<B>) -> Option<Vec<u8>> {
+        match self {
+            KeySource::ClientIp => client_ip(forwarding, req).map(|c| c.to_string().into_bytes()),
+            KeySource::Header(name) => req.headers()
+                .get(name)
+                .map(|c| c.as_bytes().to_vec()),
+            KeySource::Cookie(name) => req.headers()
+                .get_all(COOKIE)
+                .iter()
+                .filter_map(|c| c.to_str().ok())
+                .flat_map(|c| c.split(';'))
+                .filter_map(|c| c.trim().split_once('='))
+                .find(|(k, _)| k == name)
+                .map(|(_, v)| v.as_bytes().to_vec()),
+            KeySource::PathPrefix(n) => {
+                let prefix: Vec<&str> = req.uri().path()
+                    .split('/')
+                    .filter(|c| !c.is_empty())
+                    .take(*n)
+                    .collect();
+                Some(format!("/{}", prefix.join("/")).into_bytes())
+            }
+            KeySource::Query(name) => req.uri().query()?
+                .split('&')
+                .map(|c| c.split_once('=').unwrap_or((c, "")))
+                .find(|(k, _)| k == name)
+                .map(|(_, v)| v.as_bytes().to_vec()),
+        }
+    }
+}
+
+/// Build the key a request is hashed on
+///
+/// Values of all configured sources that are present in the request are
+/// combined, `None` is returned when none of them are present. The client address
+/// is resolved through the trusted proxies in `forwarding`
+pub fn request_key<B>(conf: &AffinityConf, forwarding: &ForwardingConf, req: &Request<B>) -> Option<Vec<u8>> {
+    let mut key = vec![];
+    let mut found = false;
+
+    for (i, source) in conf.key.iter().enumerate() {
+        if let Some(value) = source.extract(forwarding, req) {
+            // prefix every part with its source so `a` + `bc` differs from `ab` + `c`
+            key.extend_from_slice(&(i as u32).to_le_bytes());
+            key.extend_from_slice(&(value.len() as u32).to_le_bytes());
+            key.extend_from_slice(&value);
+            found = true;
+        }
+    }
+    found.then_some(key)
+}
+
+#[test]
+fn test_request_key() {
+    let sources = |c: &[&str]| AffinityConf { key: c.iter().map(|c| KeySource::try_from(c.to_string()).unwrap()).collect() };
+    let mut req = Request::builder()
+        .uri("/neo/feed/today?start_date=2024-01-01&user=42")
+        .header("x-user", "caleb")
+        .header("cookie", "theme=dark; session=abc123")
+        .header("x-forwarded-for", "203.0.113.9")
+        .body(())
+        .unwrap();
+    req.extensions_mut().insert(axum::extract::ConnectInfo(std::net::SocketAddr::from(([10, 0, 0, 7], 51234))));
+    let forwarding = ForwardingConf::default();
+
+    let single = |c: &str| request_key(&sources(&[c]), &forwarding, &req).map(|c| String::from_utf8_lossy(&c[8..]).to_string());
+    // the peer is not a trusted proxy, so it is the client whatever it forwards for
+    assert_eq!(single("client_ip").as_deref(), Some("10.0.0.7"));
+    assert_eq!(single("header:X-User").as_deref(), Some("caleb"));
+    assert_eq!(single("cookie:session").as_deref(), Some("abc123"));
+    assert_eq!(single("path_prefix:2").as_deref(), Some("/neo/feed"));
+    assert_eq!(single("query:user").as_deref(), Some("42"));
+    assert_eq!(single("query:missing"), None);
+    assert_eq!(single("header:x-missing"), None);
+
+    // combinations only need one present source, and differ from their parts
+    let combined = request_key(&sources(&["header:x-missing", "client_ip", "query:user"]), &forwarding, &req).unwrap();
+    assert_ne!(Some(combined), request_key(&sources(&["client_ip"]), &forwarding, &req));
+    assert_eq!(request_key(&sources(&["cookie:missing", "query:missing"]), &forwarding, &req), None);
+
+    // behind a trusted proxy the client it forwards for is the key
+    let forwarding = ForwardingConf { trusted_proxies: vec!["10.0.0.0/8".to_string().try_into().unwrap()], ..Default::default() };
+    let key = request_key(&sources(&["client_ip"]), &forwarding, &req).unwrap();
+    assert_eq!(&key[8..], b"203.0.113.9");
+
+    assert!(KeySource::try_from("header".to_string()).is_err());
+    assert!(KeySource::try_from("path_prefix:two".to_string()).is_err());
+}
@@ -0,0 +1,441 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use nanorand::{Rng, WyRand};
+use serde::Deserialize;
+use crate::config::SingleServer;
+use crate::consistent_hashing::ServerPool;
+use crate::timeouts::TimeoutConf;
+
+/// Strategies that can be selected with `balancing.strategy` or per route
+#[derive(Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum StrategyKind {
+    /// Hash the request's affinity key with the configured placement, random requests without one
+    #[default]
+    ConsistentHash,
+    /// Every server in turn
+    RoundRobin,
+    /// Every server in turn, as many times as its weight, interleaved
+    WeightedRoundRobin,
+    /// The server with the fewest in flight requests for its weight
+    LeastConnections,
+    /// A random server, weighted
+    Random,
+    /// The less loaded of two random servers
+    #[serde(alias = "p2c")]
+    PowerOfTwoChoices,
+    /// The server with the lowest peak EWMA latency times its in flight requests
+    PeakEwma,
+}
+
+/// Strategy used for requests that match no route
+#[derive(Deserialize)]
+#[derive(Debug, Clone, Default, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct BalancingConf {
+    #[serde(default)]
+    pub strategy: StrategyKind,
+}
+
+/// Settings for requests under a path prefix, written as `[[routes]]`
+#[derive(Deserialize)]
+#[derive(Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct RouteConf {
+    /// Path prefix matched on whole segments, `/neo` matches `/neo/feed` but not `/neon`
+    pub prefix: String,
+    /// Strategy for the route, `balancing.strategy` when not set
+    #[serde(default)]
+    pub strategy: Option<StrategyKind>,
+    /// Timeouts taking precedence over the server's and the global ones
+    #[serde(default)]
+    pub timeouts: TimeoutConf,
+}
+
+impl RouteConf {
+    fn matches(&self, path: &str) -> bool {
+        let prefix = self.prefix.trim_end_matches('/');
+        match path.strip_prefix(prefix) {
+            Some(rest) => rest.is_empty() || rest.starts_with('/'),
+            None => false,
+        }
+    }
+}
+
+/// Picks the server a request is proxied to
+pub trait BalancingStrategy: Send + Sync {
+    /// Pick a server from the pool, `key` is the request's affinity key if it has one
+    ///
+    /// Servers named in `exclude` are not picked, a retry excludes the servers that already failed
+    fn pick(&self, pool: &ServerPool, key: Option<&[u8]>, exclude: &[String]) -> Option<SingleServer>;
+}
+
+/// Create a strategy, every call starts with fresh state
+pub fn new_strategy(kind: StrategyKind) -> Box<dyn BalancingStrategy> {
+    match kind {
+        StrategyKind::ConsistentHash => Box::new(ConsistentHash { rand_gen: Mutex::new(WyRand::new()) }),
+        StrategyKind::RoundRobin => Box::new(RoundRobin { next: AtomicUsize::new(0) }),
+        StrategyKind::WeightedRoundRobin => Box::new(WeightedRoundRobin { current: Mutex::new(HashMap::new()) }),
+        StrategyKind::LeastConnections => Box::new(LeastConnections { rand_gen: Mutex::new(WyRand::new()) }),
+        StrategyKind::Random => Box::new(Random { rand_gen: Mutex::new(WyRand::new()) }),
+        StrategyKind::PowerOfTwoChoices => Box::new(PowerOfTwoChoices { rand_gen: Mutex::new(WyRand::new()) }),
+        StrategyKind::PeakEwma => Box::new(PeakEwma { rand_gen: Mutex::new(WyRand::new()) }),
+    }
+}
+
+/// Whether `a` has fewer in flight requests than `b` relative to their weights
+fn less_loaded(a: &SingleServer, b: &SingleServer) -> bool {
+    a.state.in_flight().saturating_mul(b.weight.max(1)) < b.state.in_flight().saturating_mul(a.weight.max(1))
+}
+
+pub struct ConsistentHash {
+    rand_gen: Mutex<WyRand>,
+}
+
+impl BalancingStrategy for ConsistentHash {
+    fn pick(&self, pool: &ServerPool, key: Option<&[u8]>, exclude: &[String]) -> Option<SingleServer> {
+        match key {
+            Some(key) => pool.get_server_container(key, exclude),
+            None => {
+                // no affinity key in the request, spread it randomly
+                let random_key = self.rand_gen.lock().unwrap().generate::<u64>();
+                pool.get_server_container(&random_key.to_le_bytes(), exclude)
+            }
+        }
+    }
+}
+
+pub struct RoundRobin {
+    next: AtomicUsize,
+}
+
+impl BalancingStrategy for RoundRobin {
+    fn pick(&self, pool: &ServerPool, _key: Option<&[u8]>, exclude: &[String]) -> Option<SingleServer> {
+        let servers = pool.eligible(exclude);
+        if servers.is_empty() {
+            return None;
+        }
+        let next = self.next.fetch_add(1, Ordering::Relaxed);
+        Some(servers[next % servers.len()].clone())
+    }
+}
+
+/// Smooth weighted round robin as done by nginx
+///
+/// Every pick adds each server's weight to its current weight, the server with
+/// the highest current weight wins and has the total weight subtracted from it.
+/// Weights 5, 1, 1 give `a a b a c a a` instead of `a a a a a b c`
+pub struct WeightedRoundRobin {
+    current: Mutex<HashMap<String, i64>>,
+}
+
+impl BalancingStrategy for WeightedRoundRobin {
+    fn pick(&self, pool: &ServerPool, _key: Option<&[u8]>, exclude: &[String]) -> Option<SingleServer> {
+        let servers = pool.eligible(exclude);
+        let mut current = self.current.lock().unwrap();
+        // excluded servers keep their current weight for the next pick
+        current.retain(|name, _| pool.servers().iter().any(|c| &c.name == name));
+
+        let mut total = 0;
+        let mut best: Option<(usize, i64)> = None;
+        for (i, server) in servers.iter().enumerate() {
+            let weight = server.weight.max(1) as i64;
+            total += weight;
+
+            let value = match current.get_mut(&server.name) {
+                Some(value) => {
+                    *value += weight;
+                    *value
+                }
+                None => {
+                    current.insert(server.name.clone(), weight);
+                    weight
+                }
+            };
+            if best.is_none_or(|(_, b)| value > b) {
+                best = Some((i, value));
+            }
+        }
+        let (i, _) = best?;
+        *current.get_mut(&servers[i].name).unwrap() -= total;
+
+        Some(servers[i].clone())
+    }
+}
+
+/// Servers with equally few connections are picked from in random order
+pub struct LeastConnections {
+    rand_gen: Mutex<WyRand>,
+}
+
+impl BalancingStrategy for LeastConnections {
+    fn pick(&self, pool: &ServerPool, _key: Option<&[u8]>, exclude: &[String]) -> Option<SingleServer> {
+        let servers = pool.eligible(exclude);
+        if servers.is_empty() {
+            return None;
+        }
+        let start = self.rand_gen.lock().unwrap().generate_range(0..servers.len());
+
+        servers[start..]
+            .iter()
+            .chain(servers[..start].iter())
+            .copied()
+            .reduce(|best, c| if less_loaded(c, best) { c } else { best })
+            .cloned()
+    }
+}
+
+pub struct Random {
+    rand_gen: Mutex<WyRand>,
+}
+
+impl BalancingStrategy for Random {
+    fn pick(&self, pool: &ServerPool, _key: Option<&[u8]>, exclude: &[String]) -> Option<SingleServer> {
+        let servers = pool.eligible(exclude);
+        let total_weight = servers.iter().fold(0usize, |sum, c| sum.saturating_add(c.weight.max(1)));
+        if total_weight == 0 {
+            return None;
+        }
+        let mut target = self.rand_gen.lock().unwrap().generate_range(0..total_weight);
+
+        servers.into_iter()
+            .find(|c| {
+                let weight = c.weight.max(1);
+                if target < weight {
+                    return true;
+                }
+                target -= weight;
+                false
+            })
+            .cloned()
+    }
+}
+
+/// Compares two distinct servers chosen uniformly at random, which avoids the herding
+/// of least connections when many balancers share backends while still steering
+/// requests away from loaded servers
+pub struct PowerOfTwoChoices {
+    rand_gen: Mutex<WyRand>,
+}
+
+impl BalancingStrategy for PowerOfTwoChoices {
+    fn pick(&self, pool: &ServerPool, _key: Option<&[u8]>, exclude: &[String]) -> Option<SingleServer> {
+        let servers = pool.eligible(exclude);
+        if servers.len() < 2 {
+            return servers.first().map(|c| (*c).clone());
+        }
+        let (first, mut second) = {
+            let mut rand_gen = self.rand_gen.lock().unwrap();
+            (rand_gen.generate_range(0..servers.len()), rand_gen.generate_range(0..servers.len() - 1))
+        };
+        if second >= first {
+            second += 1;
+        }
+        let (a, b) = (servers[first], servers[second]);
+
+        Some(if less_loaded(b, a) { b } else { a }.clone())
+    }
+}
+
+/// Latency aware selection as in Finagle's peak EWMA balancer
+///
+/// The cost of a server is its peak EWMA latency times its in flight requests plus
+/// the one being picked for, divided by its weight. Servers that have not responded
+/// yet cost nothing while idle, so new servers get probed, but a large penalty while
+/// they have requests outstanding. Requests that failed count as slow responses
+pub struct PeakEwma {
+    rand_gen: Mutex<WyRand>,
+}
+
+impl PeakEwma {
+    /// Cost of a server that has requests in flight but no latency measurement, in seconds
+    const PENALTY: f64 = 1e6;
+
+    fn cost(server: &SingleServer) -> f64 {
+        let latency = server.state.latency_ewma();
+        let in_flight = server.state.in_flight() as f64;
+
+        let cost = if latency == 0.0 && in_flight > 0.0 {
+            Self::PENALTY + in_flight
+        } else {
+            latency * (in_flight + 1.0)
+        };
+        cost / server.weight.max(1) as f64
+    }
+}
+
+impl BalancingStrategy for PeakEwma {
+    fn pick(&self, pool: &ServerPool, _key: Option<&[u8]>, exclude: &[String]) -> Option<SingleServer> {
+        let servers = pool.eligible(exclude);
+        if servers.is_empty() {
+            return None;
+        }
+        // servers with equal cost are picked from in random order
+        let start = self.rand_gen.lock().unwrap().generate_range(0..servers.len());
+
+        servers[start..]
+            .iter()
+            .chain(servers[..start].iter())
+            .map(|c| (Self::cost(c), *c))
+            .reduce(|best, c| if c.0 < best.0 { c } else { best })
+            .map(|(_, c)| c.clone())
+    }
+}
+
+/// The default strategy and the strategies of all routes
+pub struct Balancer {
+    conf: BalancingConf,
+    routes: Vec<RouteConf>,
+    default: Box<dyn BalancingStrategy>,
+    /// One strategy per route that sets one, in the order of `routes`
+    strategies: Vec<Option<Box<dyn BalancingStrategy>>>,
+}
+
+impl Balancer {
+    pub fn new(conf: &BalancingConf, routes: &[RouteConf]) -> Balancer {
+        // the longest prefix wins, so check those first
+        let mut routes = routes.to_vec();
+        routes.sort_by_key(|c| std::cmp::Reverse(c.prefix.trim_end_matches('/').len()));
+
+        Balancer {
+            conf: conf.clone(),
+            strategies: routes.iter().map(|c| c.strategy.map(new_strategy)).collect(),
+            routes,
+            default: new_strategy(conf.strategy),
+        }
+    }
+
+    /// Whether this balancer was built from the given config, routes may be in any order
+    pub fn is_built_from(&self, conf: &BalancingConf, routes: &[RouteConf]) -> bool {
+        self.conf == *conf && self.routes.len() == routes.len() && routes.iter().all(|c| self.routes.contains(c))
+    }
+
+    /// Route with the longest prefix matching a request path
+    pub fn route(&self, path: &str) -> Option<&RouteConf> {
+        self.routes.iter().find(|c| c.matches(path))
+    }
+
+    /// Strategy for a request path
+    pub fn strategy(&self, path: &str) -> &dyn BalancingStrategy {
+        self.routes
+            .iter()
+            .position(|c| c.matches(path))
+            .and_then(|i| self.strategies[i].as_deref())
+            .unwrap_or(self.default.as_ref())
+    }
+}
+
+#[cfg(test)]
+fn test_picks(kind: StrategyKind, pool: &ServerPool, n: usize) -> Vec<String> {
+    let strategy = new_strategy(kind);
+    (0..n).map(|_| strategy.pick(pool, None, &[]).unwrap().name).collect()
+}
+
+#[cfg(test)]
+fn count(picks: &[String], name: &str) -> usize {
+    picks.iter().filter(|c| *c == name).count()
+}
+
+#[test]
+fn test_round_robin() {
+    let mut pool = crate::consistent_hashing::test_pool(Default::default(), 3);
+
+    let picks = test_picks(StrategyKind::RoundRobin, &pool, 6);
+    assert_eq!(picks, ["server-0", "server-1", "server-2", "server-0", "server-1", "server-2"]);
+
+    pool.set_weight("server-0", 5);
+    let picks = test_picks(StrategyKind::WeightedRoundRobin, &pool, 7);
+    assert_eq!(picks, ["server-0", "server-0", "server-1", "server-0", "server-2", "server-0", "server-0"]);
+
+    // removed servers are forgotten, added ones join in
+    let strategy = new_strategy(StrategyKind::WeightedRoundRobin);
+    strategy.pick(&pool, None, &[]);
+    pool.remove_server("server-0");
+    pool.add_server("server-3".to_string(), "127.0.0.1".to_string(), 8003, 2);
+    let picks: Vec<String> = (0..8).map(|_| strategy.pick(&pool, None, &[]).unwrap().name).collect();
+    assert_eq!((count(&picks, "server-1"), count(&picks, "server-2"), count(&picks, "server-3")), (2, 2, 4));
+
+    // retries exclude the servers that failed
+    let empty = ServerPool::new(Default::default());
+    let failed = ["server-1".to_string(), "server-3".to_string()];
+    for kind in [StrategyKind::ConsistentHash, StrategyKind::RoundRobin, StrategyKind::WeightedRoundRobin,
+        StrategyKind::LeastConnections, StrategyKind::Random, StrategyKind::PowerOfTwoChoices, StrategyKind::PeakEwma] {
+        assert!(new_strategy(kind).pick(&empty, None, &[]).is_none(), "{kind:?}");
+        let strategy = new_strategy(kind);
+        for key in 0..20u64 {
+            assert_eq!(strategy.pick(&pool, Some(&key.to_le_bytes()), &failed).unwrap().name, "server-2", "{kind:?}");
+        }
+        assert!(strategy.pick(&pool, None, &["server-2".to_string(), failed[0].clone(), failed[1].clone()]).is_none(), "{kind:?}");
+    }
+}
+
+#[test]
+fn test_load_aware_strategies() {
+    let mut pool = crate::consistent_hashing::test_pool(Default::default(), 3);
+    let servers = pool.server_containers();
+
+    // server-0 has 4 requests, server-1 has 2 with twice the weight, server-2 has 2
+    pool.set_weight("server-1", 2);
+    let _busy: Vec<_> = [0, 0, 0, 0, 1, 1, 2, 2].iter()
+        .map(|i| servers[*i].state.start_request(&servers[*i].name))
+        .collect();
+
+    let picks = test_picks(StrategyKind::LeastConnections, &pool, 20);
+    assert_eq!(count(&picks, "server-1"), 20);
+
+    // the busiest server only wins when it is compared with itself, which never happens
+    let picks = test_picks(StrategyKind::PowerOfTwoChoices, &pool, 3000);
+    assert_eq!(count(&picks, "server-0"), 0);
+    assert!(count(&picks, "server-1") > count(&picks, "server-2"));
+
+    let picks = test_picks(StrategyKind::Random, &pool, 40_000);
+    let share = count(&picks, "server-1") as f64 / picks.len() as f64;
+    assert!((share - 0.5).abs() < 0.02, "weight 2 of 4 got {share:.3}");
+}
+
+#[test]
+fn test_peak_ewma() {
+    let ms = std::time::Duration::from_millis;
+    let pool = crate::consistent_hashing::test_pool(Default::default(), 3);
+    let servers = pool.server_containers();
+
+    // unmeasured idle servers are probed first
+    servers[0].state.observe_latency(&servers[0].name, ms(50));
+    servers[1].state.observe_latency(&servers[1].name, ms(200));
+    assert_eq!(test_picks(StrategyKind::PeakEwma, &pool, 10), vec!["server-2"; 10]);
+
+    // but not while they have a request outstanding
+    let _probe = servers[2].state.start_request(&servers[2].name);
+    assert_eq!(test_picks(StrategyKind::PeakEwma, &pool, 10), vec!["server-0"; 10]);
+
+    // with 4 requests in flight the faster server costs more than the slower idle one
+    let _busy: Vec<_> = (0..4).map(|_| servers[0].state.start_request(&servers[0].name)).collect();
+    assert_eq!(test_picks(StrategyKind::PeakEwma, &pool, 10), vec!["server-1"; 10]);
+}
+
+#[test]
+fn test_routes() {
+    let route = |prefix: &str, strategy| RouteConf { prefix: prefix.to_string(), strategy: Some(strategy), timeouts: Default::default() };
+    let routes = [
+        route("/neo", StrategyKind::RoundRobin),
+        route("/neo/feed/", StrategyKind::LeastConnections),
+        route("/apod", StrategyKind::Random),
+    ];
+    let balancer = Balancer::new(&BalancingConf::default(), &routes);
+    let route_strategy = |path: &str| balancer.route(path).and_then(|c| c.strategy);
+
+    assert_eq!(route_strategy("/neo"), Some(StrategyKind::RoundRobin));
+    assert_eq!(route_strategy("/neo/browse"), Some(StrategyKind::RoundRobin));
+    assert_eq!(route_strategy("/neo/feed"), Some(StrategyKind::LeastConnections));
+    assert_eq!(route_strategy("/neo/feed/today"), Some(StrategyKind::LeastConnections));
+    assert_eq!(route_strategy("/neon"), None);
+    assert_eq!(route_strategy("/"), None);
+
+    let mut reordered = routes.to_vec();
+    reordered.reverse();
+    assert!(balancer.is_built_from(&BalancingConf::default(), &reordered));
+    assert!(!balancer.is_built_from(&BalancingConf { strategy: StrategyKind::Random }, &routes));
+    assert!(!balancer.is_built_from(&BalancingConf::default(), &routes[1..]));
+}
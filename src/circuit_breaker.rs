@@ -0,0 +1,324 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use crate::prometheus_stats::{CIRCUIT_BREAKER_STATE, CIRCUIT_BREAKER_TRIPS};
+
+fn default_enabled() -> bool {
+    true
+}
+
+fn default_consecutive_failures() -> usize {
+    5
+}
+
+fn default_error_rate() -> f64 {
+    0.5
+}
+
+fn default_min_requests() -> usize {
+    20
+}
+
+fn default_window_secs() -> u64 {
+    10
+}
+
+fn default_cooldown_ms() -> u64 {
+    5000
+}
+
+fn default_probes() -> usize {
+    1
+}
+
+/// When a backend's circuit breaker trips and recovers, set in `[circuit_breaker]`
+///
+/// Responses with a 5xx status and requests that could not reach the backend count as failures
+#[derive(Deserialize)]
+#[derive(Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct BreakerConf {
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+    /// Failures in a row that trip the breaker
+    #[serde(default = "default_consecutive_failures")]
+    pub consecutive_failures: usize,
+    /// Fraction of failed requests in a window that trips the breaker
+    #[serde(default = "default_error_rate")]
+    pub error_rate: f64,
+    /// Requests a window needs before its error rate is considered
+    #[serde(default = "default_min_requests")]
+    pub min_requests: usize,
+    /// Length of the window the error rate is counted over
+    #[serde(default = "default_window_secs")]
+    pub window_secs: u64,
+    /// How long a tripped breaker keeps the backend out of selection before probing it
+    #[serde(default = "default_cooldown_ms")]
+    pub cooldown_ms: u64,
+    /// Probe requests let through at once while half open, all of them have to
+    /// succeed to close the breaker
+    #[serde(default = "default_probes")]
+    pub probes: usize,
+}
+
+impl Default for BreakerConf {
+    fn default() -> Self {
+        BreakerConf {
+            enabled: default_enabled(),
+            consecutive_failures: default_consecutive_failures(),
+            error_rate: default_error_rate(),
+            min_requests: default_min_requests(),
+            window_secs: default_window_secs(),
+            cooldown_ms: default_cooldown_ms(),
+            probes: default_probes(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum BreakerState {
+    /// Requests flow normally
+    Closed,
+    /// The backend is not selected until the cooldown is over
+    Open,
+    /// A limited number of probe requests decide whether to close or open again
+    HalfOpen,
+}
+
+impl BreakerState {
+    /// Value of the state gauge
+    fn gauge(&self) -> f64 {
+        match self {
+            BreakerState::Closed => 0.0,
+            BreakerState::HalfOpen => 1.0,
+            BreakerState::Open => 2.0,
+        }
+    }
+}
+
+/// Counters of a breaker, as shown by the admin endpoint
+#[derive(Serialize)]
+#[derive(Debug, Clone)]
+pub struct BreakerSnapshot {
+    pub state: BreakerState,
+    pub consecutive_failures: usize,
+    /// Requests and failures in the current window
+    pub requests: usize,
+    pub failures: usize,
+}
+
+#[derive(Debug)]
+struct Breaker {
+    state: BreakerState,
+    consecutive_failures: usize,
+    window_start: Instant,
+    requests: usize,
+    failures: usize,
+    /// When an open breaker starts letting probes through
+    open_until: Instant,
+    cooldown: Duration,
+    /// When the last probe was sent, probes that never finish are given up after a cooldown
+    probe_sent: Instant,
+    /// Probes allowed at once and in flight, and probes that succeeded while half open
+    max_probes: usize,
+    probes_in_flight: usize,
+    probe_successes: usize,
+}
+
+/// Circuit breaker of a backend
+///
+/// Everything a breaker needs to decide whether it lets requests through is stored
+/// when it trips, so server selection does not need the config
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    inner: Mutex<Breaker>,
+}
+
+impl Default for CircuitBreaker {
+    fn default() -> Self {
+        let now = Instant::now();
+        CircuitBreaker {
+            inner: Mutex::new(Breaker {
+                state: BreakerState::Closed,
+                consecutive_failures: 0,
+                window_start: now,
+                requests: 0,
+                failures: 0,
+                open_until: now,
+                cooldown: Duration::ZERO,
+                probe_sent: now,
+                max_probes: 0,
+                probes_in_flight: 0,
+                probe_successes: 0,
+            }),
+        }
+    }
+}
+
+impl Breaker {
+    /// An open breaker becomes half open once its cooldown is over
+    fn refresh(&mut self, name: &str) {
+        let now = Instant::now();
+        if self.state == BreakerState::Open && now >= self.open_until {
+            info!("Circuit breaker of {} is half open, probing it", name);
+            self.set_state(name, BreakerState::HalfOpen);
+            self.probes_in_flight = 0;
+            self.probe_successes = 0;
+        }
+        // a cancelled probe never reports back
+        if self.state == BreakerState::HalfOpen && self.probes_in_flight > 0 && now >= self.probe_sent + self.cooldown {
+            self.probes_in_flight = 0;
+        }
+    }
+
+    fn set_state(&mut self, name: &str, state: BreakerState) {
+        self.state = state;
+        CIRCUIT_BREAKER_STATE.with_label_values(&[name]).set(state.gauge());
+    }
+
+    fn reset_counts(&mut self) {
+        self.consecutive_failures = 0;
+        self.window_start = Instant::now();
+        self.requests = 0;
+        self.failures = 0;
+    }
+
+    fn trip(&mut self, name: &str, conf: &BreakerConf) {
+        warn!("Circuit breaker of {} tripped after {} failures in a row, {} of {} requests failed",
+            name, self.consecutive_failures, self.failures, self.requests);
+        CIRCUIT_BREAKER_TRIPS.with_label_values(&[name]).inc();
+        self.set_state(name, BreakerState::Open);
+        self.cooldown = Duration::from_millis(conf.cooldown_ms);
+        self.open_until = Instant::now() + self.cooldown;
+        self.max_probes = conf.probes.max(1);
+        self.reset_counts();
+    }
+}
+
+impl CircuitBreaker {
+    /// Whether the backend may be selected
+    pub fn allows_requests(&self, name: &str) -> bool {
+        let mut breaker = self.inner.lock().unwrap();
+        breaker.refresh(name);
+        match breaker.state {
+            BreakerState::Closed => true,
+            BreakerState::Open => false,
+            BreakerState::HalfOpen => breaker.probes_in_flight < breaker.max_probes,
+        }
+    }
+
+    /// Call when a request is sent to the backend, returns whether it is a probe
+    pub fn start(&self, name: &str) -> bool {
+        let mut breaker = self.inner.lock().unwrap();
+        breaker.refresh(name);
+        let probe = breaker.state == BreakerState::HalfOpen;
+        if probe {
+            breaker.probes_in_flight += 1;
+            breaker.probe_sent = Instant::now();
+        }
+        probe
+    }
+
+    /// Record the outcome of a request started with [`start`](Self::start)
+    pub fn record(&self, name: &str, conf: &BreakerConf, success: bool, probe: bool) {
+        if !conf.enabled {
+            return;
+        }
+        let mut breaker = self.inner.lock().unwrap();
+        match breaker.state {
+            BreakerState::Closed => {
+                if breaker.window_start.elapsed() >= Duration::from_secs(conf.window_secs) {
+                    breaker.window_start = Instant::now();
+                    breaker.requests = 0;
+                    breaker.failures = 0;
+                }
+                breaker.requests += 1;
+                if success {
+                    breaker.consecutive_failures = 0;
+                } else {
+                    breaker.failures += 1;
+                    breaker.consecutive_failures += 1;
+                }
+                let error_rate = breaker.failures as f64 / breaker.requests as f64;
+                if breaker.consecutive_failures >= conf.consecutive_failures
+                    || (breaker.requests >= conf.min_requests && error_rate >= conf.error_rate) {
+                    breaker.trip(name, conf);
+                }
+            }
+            // requests sent before the breaker tripped do not count
+            BreakerState::HalfOpen if probe => {
+                breaker.probes_in_flight = breaker.probes_in_flight.saturating_sub(1);
+                if !success {
+                    breaker.trip(name, conf);
+                    return;
+                }
+                breaker.probe_successes += 1;
+                if breaker.probe_successes >= breaker.max_probes {
+                    info!("Circuit breaker of {} closed, probes succeeded", name);
+                    breaker.set_state(name, BreakerState::Closed);
+                    breaker.reset_counts();
+                }
+            }
+            _ => {}
+        }
+    }
+
+    pub fn snapshot(&self, name: &str) -> BreakerSnapshot {
+        let mut breaker = self.inner.lock().unwrap();
+        breaker.refresh(name);
+        BreakerSnapshot {
+            state: breaker.state,
+            consecutive_failures: breaker.consecutive_failures,
+            requests: breaker.requests,
+            failures: breaker.failures,
+        }
+    }
+}
+
+#[test]
+fn test_circuit_breaker() {
+    let conf = BreakerConf { consecutive_failures: 3, min_requests: 10, error_rate: 0.5, cooldown_ms: 50, probes: 2, ..Default::default() };
+    let breaker = CircuitBreaker::default();
+    let request = |success: bool| {
+        let probe = breaker.start("cb-test");
+        breaker.record("cb-test", &conf, success, probe);
+    };
+    let state = || breaker.snapshot("cb-test").state;
+
+    // failures in a row
+    request(false);
+    request(false);
+    request(true);
+    request(false);
+    request(false);
+    assert_eq!(state(), BreakerState::Closed);
+    request(false);
+    assert_eq!(state(), BreakerState::Open);
+    assert!(!breaker.allows_requests("cb-test"));
+
+    // after the cooldown two probes go through, a failing one opens it again
+    std::thread::sleep(Duration::from_millis(60));
+    assert!(breaker.allows_requests("cb-test"));
+    let probes = [breaker.start("cb-test"), breaker.start("cb-test")];
+    assert_eq!(probes, [true, true]);
+    assert!(!breaker.allows_requests("cb-test"));
+    breaker.record("cb-test", &conf, true, true);
+    breaker.record("cb-test", &conf, false, true);
+    assert_eq!(state(), BreakerState::Open);
+
+    std::thread::sleep(Duration::from_millis(60));
+    request(true);
+    assert_eq!(state(), BreakerState::HalfOpen);
+    request(true);
+    assert_eq!(state(), BreakerState::Closed);
+
+    // error rate over the window, without failing 3 times in a row
+    for i in 0..10 {
+        request(i % 2 == 0);
+    }
+    assert_eq!(state(), BreakerState::Open);
+    assert_eq!(CIRCUIT_BREAKER_TRIPS.with_label_values(&["cb-test"]).get(), 3.0);
+}
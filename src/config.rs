@@ -1,50 +1,682 @@
-use std::collections::HashMap;
+use std::collections::BTreeMap;
+use std::fmt::{Display, Formatter};
 use std::fs::read_to_string;
-use std::sync::{RwLock};
+use std::path::{PathBuf};
+use std::sync::{Arc, RwLock};
+use axum::http::{HeaderValue, Method};
 use log::{info, trace};
 use serde::Deserialize;
+use toml::{Table, Value};
+use crate::affinity::AffinityConf;
+use crate::balancing::{BalancingConf, RouteConf};
+use crate::circuit_breaker::BreakerConf;
+use crate::forwarding::ForwardingConf;
+use crate::hashers::HasherKind;
+use crate::heartbeat::HealthConf;
+use crate::outlier::OutlierConf;
+use crate::placement::PlacementKind;
+use crate::proxy::{HttpClient, LimitsConf, PoolConf};
+use crate::replicas::{is_valid_name, ReplicaConf};
+use crate::retry::RetryConf;
+use crate::runtime::RuntimeKind;
+use crate::server_state::ServerState;
+use crate::timeouts::TimeoutConf;
+
+/// Environment variable holding the config path, used when `--config` is not passed
+const CONFIG_PATH_ENV: &str = "KLEIN_CONFIG";
+/// Prefix of environment variables overriding config keys
+const ENV_PREFIX: &str = "KLEIN_";
+/// Config path used when neither `--config` nor `KLEIN_CONFIG` is set
+const DEFAULT_CONFIG_PATH: &str = "klein_config.toml";
+
+fn default_weight() -> usize {
+    1
+}
+
+/// Largest weight a server can have, every unit of weight costs virtual servers and buckets
+pub const MAX_WEIGHT: usize = 1000;
 
 #[derive(Deserialize)]
 #[derive(Debug, Clone)]
+#[serde(deny_unknown_fields)]
 pub struct SingleServer {
     pub host: String,
     pub port: u16,
     pub name: String,
+    /// Relative share of traffic this server should get, from 1 to [`MAX_WEIGHT`]
+    #[serde(default = "default_weight")]
+    pub weight: usize,
+    /// Connection pool settings overriding the global `[pool]` ones
+    #[serde(default)]
+    pub pool: PoolConf,
+    /// Timeouts overriding the global `[timeouts]` ones
+    #[serde(default)]
+    pub timeouts: TimeoutConf,
+    /// Assigned by the server pool when the server is added
+    #[serde(skip)]
     pub id: usize,
+    /// Live state, shared by every clone of this server
+    #[serde(skip)]
+    pub state: Arc<ServerState>,
 }
 
-/// Server configuration
+fn default_slots() -> usize {
+    65536
+}
+
+fn default_virtual_nodes() -> usize {
+    200
+}
+
+/// Consistent hash ring parameters
 #[derive(Deserialize)]
+#[derive(Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct RingConf {
+    /// Algorithm that assigns request keys to servers
+    #[serde(default)]
+    pub placement: PlacementKind,
+    /// Number of slots on the ring, or the minimum size of the Maglev table
+    #[serde(default = "default_slots")]
+    pub slots: usize,
+    /// Number of virtual servers placed on the ring for every server
+    #[serde(default = "default_virtual_nodes")]
+    pub virtual_nodes: usize,
+    /// Hash function used to place servers and requests
+    #[serde(default)]
+    pub hasher: HasherKind,
+    /// Seed of the hash function, changing it reshuffles the ring
+    #[serde(default)]
+    pub seed: u64,
+    /// Second half of the SipHash key, the first half is `seed`
+    #[serde(default)]
+    pub key: u64,
+    /// Enables consistent hashing with bounded loads, a server whose in flight requests
+    /// would exceed `(1 + load_epsilon)` times its fair share is skipped
+    #[serde(default)]
+    pub load_epsilon: Option<f64>,
+}
+
+impl RingConf {
+    /// Total weight of the servers that fit, the ring needs `virtual_nodes` slots for every
+    /// unit of weight and Maglev a table entry, the other placements have no limit
+    pub fn capacity(&self) -> usize {
+        match self.placement {
+            PlacementKind::Ring => self.slots / self.virtual_nodes.max(1),
+            PlacementKind::Maglev => self.slots,
+            PlacementKind::Rendezvous | PlacementKind::Jump => usize::MAX,
+        }
+    }
+}
+
+impl Default for RingConf {
+    fn default() -> Self {
+        RingConf {
+            placement: PlacementKind::default(),
+            slots: default_slots(),
+            virtual_nodes: default_virtual_nodes(),
+            hasher: HasherKind::default(),
+            seed: 0,
+            key: 0,
+            load_epsilon: None,
+        }
+    }
+}
+
+/// Server configuration
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
 pub struct AppConf {
     pub(crate) port: u16,
     pub(crate) host: String,
-    //pub(crate) servers: HashMap<String, SingleServer>,
+    /// Statically declared backends, keyed by the `[servers.<key>]` table name
+    #[serde(default)]
+    pub(crate) servers: BTreeMap<String, SingleServer>,
+    /// Hash ring parameters
+    #[serde(default)]
+    pub(crate) ring: RingConf,
+    /// What part of a request is hashed to pick a server
+    #[serde(default)]
+    pub(crate) affinity: AffinityConf,
+    /// Strategy for requests that match no route
+    #[serde(default)]
+    pub(crate) balancing: BalancingConf,
+    /// Strategies for path prefixes
+    #[serde(default)]
+    pub(crate) routes: Vec<RouteConf>,
+    /// Connection pool settings for every backend
+    #[serde(default)]
+    pub(crate) pool: PoolConf,
+    /// Maximum request and response body sizes
+    #[serde(default)]
+    pub(crate) limits: LimitsConf,
+    /// Forwarding headers added to proxied requests
+    #[serde(default)]
+    pub(crate) forwarding: ForwardingConf,
+    /// Upstream timeouts for every backend
+    #[serde(default)]
+    pub(crate) timeouts: TimeoutConf,
+    /// Retrying failed requests on another backend
+    #[serde(default)]
+    pub(crate) retries: RetryConf,
+    /// Taking failing backends out of selection
+    #[serde(default)]
+    pub(crate) circuit_breaker: BreakerConf,
+    /// Background health checks of every backend
+    #[serde(default)]
+    pub(crate) health_check: HealthConf,
+    /// Ejecting backends that fail live traffic
+    #[serde(default)]
+    pub(crate) outlier_detection: OutlierConf,
+    /// Replica containers and keeping enough of them running
+    #[serde(default)]
+    pub(crate) replicas: ReplicaConf,
 }
 
 pub struct AppConfig {
+    /// Loader used to build this config, re-run on reload
+    pub(crate) loader: ConfigLoader,
     pub(crate) port: u16,
     pub(crate) host: String,
     pub(crate) servers: RwLock<Vec<SingleServer>>,
+    /// Ring parameters the server pool starts with
+    pub(crate) ring: RingConf,
+    pub(crate) affinity: AffinityConf,
+    pub(crate) balancing: BalancingConf,
+    pub(crate) routes: Vec<RouteConf>,
+    /// Global pool settings, replaced on reload
+    pub(crate) pool: RwLock<PoolConf>,
+    pub(crate) limits: LimitsConf,
+    pub(crate) forwarding: ForwardingConf,
+    /// Global timeouts, replaced on reload
+    pub(crate) timeouts: RwLock<TimeoutConf>,
+    pub(crate) retries: RetryConf,
+    pub(crate) circuit_breaker: BreakerConf,
+    pub(crate) health_check: HealthConf,
+    pub(crate) outlier_detection: OutlierConf,
+    pub(crate) replicas: ReplicaConf,
 }
 
-impl From<AppConf> for AppConfig {
-    fn from(value: AppConf) -> Self {
+impl AppConfig {
+    /// Timeouts of a server, the ones it does not set taken from the global ones
+    pub fn timeouts(&self, server: &SingleServer) -> TimeoutConf {
+        server.timeouts.or(&self.timeouts.read().unwrap())
+    }
+
+    /// Client for a server, with its own pool settings and connect timeout or the global ones
+    ///
+    /// Route timeouts are left out on purpose, the connections are shared by all routes
+    pub fn client(&self, server: &SingleServer) -> HttpClient {
+        let pool = server.pool.or(&self.pool.read().unwrap());
+        server.state.client(&pool, self.timeouts(server).connect())
+    }
+
+    fn new(loader: ConfigLoader, value: AppConf) -> Self {
         AppConfig {
+            loader,
             port: value.port,
             host: value.host,
-            servers: RwLock::new(vec![]),
+            servers: RwLock::new(value.servers.into_values().collect()),
+            ring: value.ring,
+            affinity: value.affinity,
+            balancing: value.balancing,
+            routes: value.routes,
+            pool: RwLock::new(value.pool),
+            limits: value.limits,
+            forwarding: value.forwarding,
+            timeouts: RwLock::new(value.timeouts),
+            retries: value.retries,
+            circuit_breaker: value.circuit_breaker,
+            health_check: value.health_check,
+            outlier_detection: value.outlier_detection,
+            replicas: value.replicas,
+        }
+    }
+}
+
+/// Errors produced while loading the configuration
+///
+/// `key` is the dotted path of the offending key, e.g. `servers.main.port`
+/// and `origin` is the layer that set it, the file path, an environment
+/// variable or a command line flag
+#[derive(Debug)]
+pub enum ConfigError {
+    /// The config file could not be read
+    Io { path: PathBuf, error: std::io::Error },
+    /// The config file is not valid toml
+    Syntax { path: PathBuf, message: String },
+    /// A command line argument could not be parsed
+    Cli { flag: String, message: String },
+    /// An override names a key that cannot hold a value
+    Override { origin: String, key: String, message: String },
+    /// A key that klein does not know about
+    UnknownField { origin: String, key: String },
+    /// A key whose value is missing, of the wrong type or out of range
+    Invalid { origin: String, key: String, message: String },
+    /// Two servers declare the same name
+    DuplicateServer { name: String, first: String, second: String },
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigError::Io { path, error } => write!(f, "Error reading config file {}: {}", path.display(), error),
+            ConfigError::Syntax { path, message } => write!(f, "Error parsing config file {}: {}", path.display(), message),
+            ConfigError::Cli { flag, message } => write!(f, "Invalid command line argument {}: {}", flag, message),
+            ConfigError::Override { origin, key, message } => write!(f, "{}: cannot set `{}`: {}", origin, key, message),
+            ConfigError::UnknownField { origin, key } => write!(f, "{}: unknown key `{}`", origin, key),
+            ConfigError::Invalid { origin, key, message } => write!(f, "{}: invalid value for `{}`: {}", origin, key, message),
+            ConfigError::DuplicateServer { name, first, second } => write!(f, "Duplicate server name `{}` in `{}` and `{}`", name, first, second),
         }
     }
 }
 
-pub fn read_config() -> Result<AppConfig, String> {
-    info!("Reading config");
-    let file_contents = read_to_string("/home/caleb/RustroverProjects/klein/klein_config.toml").map_err(|e| format!("Error reading file {}", e))?;
-    let config: AppConf = toml::from_str(&file_contents).map_err(|e| format!("Error occurred when parsing toml config: {e}"))?;
+impl std::error::Error for ConfigError {}
+
+/// A single key set by an environment variable or command line flag
+#[derive(Debug, Clone)]
+pub struct Override {
+    /// Where the override came from, e.g. `KLEIN_PORT` or `--set`
+    origin: String,
+    key: Vec<String>,
+    value: String,
+}
+
+impl Override {
+    fn key(&self) -> String {
+        self.key.join(".")
+    }
+}
+
+/// Builds an [`AppConf`] from layered sources
+///
+/// The config file is read first, then `KLEIN_*` environment variables and
+/// finally command line flags are applied on top of it, later layers win.
+///
+/// Environment variables map to keys by stripping the `KLEIN_` prefix, lowercasing
+/// and splitting on double underscores, so `KLEIN_SERVERS__MAIN__PORT=8000` sets
+/// `servers.main.port`. Values are parsed as toml values and fall back to plain strings.
+#[derive(Debug, Clone)]
+pub struct ConfigLoader {
+    /// File the configuration is read from
+    pub(crate) path: PathBuf,
+    /// Whether the path was asked for explicitly, a missing default file is not an error
+    path_required: bool,
+    /// Overrides from the command line, applied after the environment
+    cli_overrides: Vec<Override>,
+}
+
+impl ConfigLoader {
+    /// Create a loader from the process' command line arguments
+    ///
+    /// Supported flags are `--config <path>` (or `-c <path>`), `--host <host>`,
+    /// `--port <port>` and `--set <key>=<value>` which may be repeated
+    pub fn from_args() -> Result<ConfigLoader, ConfigError> {
+        let mut args = pico_args::Arguments::from_env();
+        let cli_error = |flag: &str, e: pico_args::Error| ConfigError::Cli { flag: flag.to_string(), message: e.to_string() };
+
+        let cli_path: Option<PathBuf> = args
+            .opt_value_from_str(["-c", "--config"])
+            .map_err(|e| cli_error("--config", e))?;
+
+        let mut cli_overrides = vec![];
+        for (flag, key) in [("--host", "host"), ("--port", "port")] {
+            let value: Option<String> = args.opt_value_from_str(flag).map_err(|e| cli_error(flag, e))?;
+            if let Some(value) = value {
+                cli_overrides.push(Override { origin: flag.to_string(), key: vec![key.to_string()], value });
+            }
+        }
+        let sets: Vec<String> = args.values_from_str("--set").map_err(|e| cli_error("--set", e))?;
+        for set in sets {
+            let (key, value) = set.split_once('=').ok_or_else(|| ConfigError::Cli {
+                flag: "--set".to_string(),
+                message: format!("expected <key>=<value>, got `{set}`"),
+            })?;
+            cli_overrides.push(Override {
+                origin: "--set".to_string(),
+                key: key.split('.').map(|c| c.trim().to_string()).collect(),
+                value: value.to_string(),
+            });
+        }
+
+        let remaining = args.finish();
+        if let Some(arg) = remaining.first() {
+            return Err(ConfigError::Cli { flag: arg.to_string_lossy().to_string(), message: "unknown argument".to_string() });
+        }
+
+        let (path, path_required) = match cli_path {
+            Some(path) => (path, true),
+            None => match std::env::var(CONFIG_PATH_ENV) {
+                Ok(path) => (PathBuf::from(path), true),
+                Err(_) => (PathBuf::from(DEFAULT_CONFIG_PATH), false),
+            },
+        };
+        Ok(ConfigLoader { path, path_required, cli_overrides })
+    }
+
+    /// Run all layers and return the validated configuration
+    pub fn load(&self) -> Result<AppConf, ConfigError> {
+        let table = self.read_file()?;
+        let env_overrides = env_overrides(std::env::vars());
+        let overrides: Vec<&Override> = env_overrides.iter().chain(self.cli_overrides.iter()).collect();
+
+        build_conf(table, &overrides, &self.path.display().to_string())
+    }
+
+    fn read_file(&self) -> Result<Table, ConfigError> {
+        let contents = match read_to_string(&self.path) {
+            Ok(c) => c,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound && !self.path_required => {
+                info!("No config file at {}, using environment and flags only", self.path.display());
+                return Ok(Table::new());
+            }
+            Err(error) => return Err(ConfigError::Io { path: self.path.clone(), error }),
+        };
+        contents.parse::<Table>().map_err(|e| ConfigError::Syntax { path: self.path.clone(), message: e.to_string() })
+    }
+}
+
+/// Collect `KLEIN_*` variables into overrides
+fn env_overrides(vars: impl Iterator<Item=(String, String)>) -> Vec<Override> {
+    let mut overrides: Vec<Override> = vars
+        .filter(|(k, _)| k.starts_with(ENV_PREFIX) && k != CONFIG_PATH_ENV)
+        .map(|(k, value)| Override {
+            key: k[ENV_PREFIX.len()..].to_lowercase().split("__").map(str::to_string).collect(),
+            origin: k,
+            value,
+        })
+        .collect();
+    // environment order is unspecified, keep the result stable
+    overrides.sort_by(|a, b| a.origin.cmp(&b.origin));
+    overrides
+}
+
+/// Interpret an override value as toml, falling back to a plain string
+fn parse_value(raw: &str) -> Value {
+    match format!("v = {raw}").parse::<Table>() {
+        Ok(mut c) => c.remove("v").unwrap_or_else(|| Value::String(raw.to_string())),
+        Err(_) => Value::String(raw.to_string()),
+    }
+}
+
+fn apply_override(table: &mut Table, o: &Override) -> Result<(), ConfigError> {
+    let error = |message: &str| ConfigError::Override { origin: o.origin.clone(), key: o.key(), message: message.to_string() };
+
+    let (last, parents) = o.key.split_last().ok_or_else(|| error("empty key"))?;
+    if o.key.iter().any(|c| c.is_empty()) {
+        return Err(error("empty key segment"));
+    }
+    let mut current = table;
+    for part in parents {
+        current = current
+            .entry(part.as_str())
+            .or_insert_with(|| Value::Table(Table::new()))
+            .as_table_mut()
+            .ok_or_else(|| error(&format!("`{part}` is not a table")))?;
+    }
+    current.insert(last.to_string(), parse_value(&o.value));
+    Ok(())
+}
+
+/// Apply overrides on top of the file contents, then deserialize and validate the result
+fn build_conf(mut table: Table, overrides: &[&Override], file_origin: &str) -> Result<AppConf, ConfigError> {
+    for o in overrides {
+        apply_override(&mut table, o)?;
+    }
+    // the last layer that touched a key (or one of its parents) is blamed for it
+    let origin_of = |key: &str| overrides.iter().rev()
+        .find(|o| key == o.key() || key.starts_with(&format!("{}.", o.key())))
+        .map(|o| o.origin.clone())
+        .unwrap_or_else(|| file_origin.to_string());
+
+    let conf = deserialize_conf(table, &origin_of)?;
+    validate(&conf, &origin_of)?;
+    Ok(conf)
+}
+
+fn deserialize_conf(table: Table, origin_of: &dyn Fn(&str) -> String) -> Result<AppConf, ConfigError> {
+    serde_path_to_error::deserialize(Value::Table(table)).map_err(|e| {
+        let path = e.path().to_string();
+        let message = e.into_inner().to_string();
+
+        if message.starts_with("unknown field") {
+            return ConfigError::UnknownField { origin: origin_of(&path), key: path };
+        }
+        ConfigError::Invalid { origin: origin_of(&path), key: path, message }
+    })
+}
+
+fn validate(conf: &AppConf, origin_of: &dyn Fn(&str) -> String) -> Result<(), ConfigError> {
+    let invalid = |key: String, message: &str| ConfigError::Invalid { origin: origin_of(&key), key, message: message.to_string() };
+
+    if conf.port == 0 {
+        return Err(invalid("port".to_string(), "port must not be 0"));
+    }
+    if conf.host.trim().is_empty() {
+        return Err(invalid("host".to_string(), "host must not be empty"));
+    }
+    if conf.ring.slots == 0 {
+        return Err(invalid("ring.slots".to_string(), "the ring needs at least one slot"));
+    }
+    if conf.ring.virtual_nodes == 0 {
+        return Err(invalid("ring.virtual_nodes".to_string(), "every server needs at least one virtual node"));
+    }
+    if conf.ring.load_epsilon.is_some_and(|c| !(c > 0.0 && c.is_finite())) {
+        return Err(invalid("ring.load_epsilon".to_string(), "must be a positive number"));
+    }
+    if conf.forwarding.via.trim().is_empty() || HeaderValue::from_str(&conf.forwarding.via).is_err() {
+        return Err(invalid("forwarding.via".to_string(), "must be a non-empty header value"));
+    }
+    if conf.retries.max_attempts == 0 {
+        return Err(invalid("retries.max_attempts".to_string(), "must be at least 1, 1 disables retries"));
+    }
+    if conf.retries.retry_on.iter().any(|c| !(500..=599).contains(c)) {
+        return Err(invalid("retries.retry_on".to_string(), "only 5xx statuses can be retried"));
+    }
+    if !(conf.retries.budget_ratio >= 0.0 && conf.retries.budget_ratio.is_finite()) {
+        return Err(invalid("retries.budget_ratio".to_string(), "must be a positive number"));
+    }
+    if conf.circuit_breaker.consecutive_failures == 0 {
+        return Err(invalid("circuit_breaker.consecutive_failures".to_string(), "must be at least 1"));
+    }
+    if !(conf.circuit_breaker.error_rate > 0.0 && conf.circuit_breaker.error_rate <= 1.0) {
+        return Err(invalid("circuit_breaker.error_rate".to_string(), "must be a fraction above 0 and at most 1"));
+    }
+    if conf.circuit_breaker.window_secs == 0 {
+        return Err(invalid("circuit_breaker.window_secs".to_string(), "must be at least 1"));
+    }
+    if conf.circuit_breaker.probes == 0 {
+        return Err(invalid("circuit_breaker.probes".to_string(), "must be at least 1"));
+    }
+    if conf.health_check.interval_ms == 0 {
+        return Err(invalid("health_check.interval_ms".to_string(), "must be at least 1"));
+    }
+    if conf.health_check.timeout_ms == 0 {
+        return Err(invalid("health_check.timeout_ms".to_string(), "must be at least 1"));
+    }
+    if !conf.health_check.path.starts_with('/') {
+        return Err(invalid("health_check.path".to_string(), "must start with /"));
+    }
+    if Method::from_bytes(conf.health_check.method.as_bytes()).is_err() {
+        return Err(invalid("health_check.method".to_string(), "not a valid HTTP method"));
+    }
+    if conf.health_check.expected_status.iter().any(|c| !(100..=599).contains(c)) {
+        return Err(invalid("health_check.expected_status".to_string(), "statuses must be between 100 and 599"));
+    }
+    if conf.health_check.healthy_threshold == 0 || conf.health_check.unhealthy_threshold == 0 {
+        let key = if conf.health_check.healthy_threshold == 0 { "healthy_threshold" } else { "unhealthy_threshold" };
+        return Err(invalid(format!("health_check.{key}"), "must be at least 1"));
+    }
+    if conf.outlier_detection.interval_ms == 0 {
+        return Err(invalid("outlier_detection.interval_ms".to_string(), "must be at least 1"));
+    }
+    if conf.outlier_detection.max_ejection_percent > 100 {
+        return Err(invalid("outlier_detection.max_ejection_percent".to_string(), "must be at most 100"));
+    }
+    if !(conf.outlier_detection.success_rate_stdev_factor >= 0.0 && conf.outlier_detection.success_rate_stdev_factor.is_finite()) {
+        return Err(invalid("outlier_detection.success_rate_stdev_factor".to_string(), "must be a positive number"));
+    }
+    if conf.replicas.image.trim().is_empty() {
+        return Err(invalid("replicas.image".to_string(), "must not be empty"));
+    }
+    if conf.replicas.container_port == 0 {
+        return Err(invalid("replicas.container_port".to_string(), "port must not be 0"));
+    }
+    if conf.replicas.runtime == RuntimeKind::LocalProcess && conf.replicas.command.is_empty() {
+        return Err(invalid("replicas.command".to_string(), "the local_process runtime needs a command to run"));
+    }
+    if conf.replicas.port_range.0 == 0 || conf.replicas.port_range.0 > conf.replicas.port_range.1 {
+        return Err(invalid("replicas.port_range".to_string(), "must be [first, last] with 0 < first <= last"));
+    }
+    if conf.replicas.host.trim().is_empty() {
+        return Err(invalid("replicas.host".to_string(), "must not be empty"));
+    }
+    if conf.replicas.interval_ms == 0 {
+        return Err(invalid("replicas.interval_ms".to_string(), "must be at least 1"));
+    }
+    if conf.replicas.startup_timeout_ms == 0 {
+        return Err(invalid("replicas.startup_timeout_ms".to_string(), "must be at least 1"));
+    }
+    if !is_valid_name(&conf.replicas.name_prefix) {
+        return Err(invalid("replicas.name_prefix".to_string(), "must be letters, digits, - and _ only"));
+    }
+    if let Some(name) = conf.timeouts.zero().next() {
+        return Err(invalid(format!("timeouts.{name}"), "must be at least 1"));
+    }
+    if conf.pool.idle_timeout_secs == Some(0) {
+        return Err(invalid("pool.idle_timeout_secs".to_string(), "must be at least 1"));
+    }
+    for (i, route) in conf.routes.iter().enumerate() {
+        if !route.prefix.starts_with('/') {
+            return Err(invalid(format!("routes[{i}].prefix"), "prefix must start with `/`"));
+        }
+        if let Some(name) = route.timeouts.zero().next() {
+            return Err(invalid(format!("routes[{i}].timeouts.{name}"), "must be at least 1"));
+        }
+        if route.timeouts.connect_ms.is_some() {
+            return Err(invalid(format!("routes[{i}].timeouts.connect_ms"), "connections are shared by all routes, set it globally or per server"));
+        }
+        if conf.routes[..i].iter().any(|c| c.prefix.trim_end_matches('/') == route.prefix.trim_end_matches('/')) {
+            return Err(invalid(format!("routes[{i}].prefix"), "another route has the same prefix"));
+        }
+    }
+    let mut names: BTreeMap<&str, &str> = BTreeMap::new();
+
+    for (key, server) in &conf.servers {
+        if server.port == 0 {
+            return Err(invalid(format!("servers.{key}.port"), "port must not be 0"));
+        }
+        if server.host.trim().is_empty() {
+            return Err(invalid(format!("servers.{key}.host"), "host must not be empty"));
+        }
+        if server.name.trim().is_empty() {
+            return Err(invalid(format!("servers.{key}.name"), "name must not be empty"));
+        }
+        if let Some(name) = server.timeouts.zero().next() {
+            return Err(invalid(format!("servers.{key}.timeouts.{name}"), "must be at least 1"));
+        }
+        if server.pool.idle_timeout_secs == Some(0) {
+            return Err(invalid(format!("servers.{key}.pool.idle_timeout_secs"), "must be at least 1"));
+        }
+        if server.weight == 0 || server.weight > MAX_WEIGHT {
+            return Err(invalid(format!("servers.{key}.weight"), &format!("weight must be between 1 and {MAX_WEIGHT}")));
+        }
+        if let Some(first) = names.insert(&server.name, key) {
+            return Err(ConfigError::DuplicateServer {
+                name: server.name.clone(),
+                first: format!("servers.{first}"),
+                second: format!("servers.{key}"),
+            });
+        }
+    }
+    // servers that do not fit would silently get no keys
+    let total_weight = conf.servers.values().fold(0usize, |sum, c| sum.saturating_add(c.weight));
+    if total_weight > conf.ring.capacity() {
+        return Err(invalid("ring.slots".to_string(), &format!("servers with a total weight of {} need more than the {} slots there are, raise ring.slots or lower ring.virtual_nodes",
+            total_weight, conf.ring.slots)));
+    }
+    Ok(())
+}
+
+pub fn read_config(loader: ConfigLoader) -> Result<AppConfig, ConfigError> {
+    info!("Reading config from {}", loader.path.display());
+    let config = loader.load()?;
     info!("Port:{}",config.port);
     info!("Host:{}",config.host);
-    // info!("Servers: {:#?}",config.servers);
+    info!("Servers: {:#?}",config.servers);
+    info!("Ring: {:?}",config.ring);
+    info!("Affinity key: {:?}",config.affinity.key);
+    info!("Balancing: {:?}, routes: {:?}",config.balancing.strategy,config.routes);
+    info!("Pool: {:?}",config.pool);
+    info!("Limits: {:?}",config.limits);
+    info!("Forwarding: {:?}",config.forwarding);
+    info!("Timeouts: {:?}",config.timeouts);
+    info!("Retries: {:?}",config.retries);
+    info!("Circuit breaker: {:?}",config.circuit_breaker);
+    info!("Health checks: {:?}",config.health_check);
+    info!("Outlier detection: {:?}",config.outlier_detection);
+    info!("Replicas: {:?}",config.replicas);
     trace!("finished reading");
 
-    return Ok(AppConfig::from(config));
-}
\ No newline at end of file
+    Ok(AppConfig::new(loader, config))
+}
+
+#[cfg(test)]
+pub fn load_str(contents: &str, vars: &[(&str, &str)]) -> Result<AppConf, ConfigError> {
+    let overrides = env_overrides(vars.iter().map(|(k, v)| (k.to_string(), v.to_string())));
+    build_conf(contents.parse::<Table>().unwrap(), &overrides.iter().collect::<Vec<_>>(), "test")
+}
+
+#[cfg(test)]
+pub fn test_app_config(contents: &str) -> AppConfig {
+    let loader = ConfigLoader { path: PathBuf::from("test"), path_required: false, cli_overrides: vec![] };
+    AppConfig::new(loader, load_str(contents, &[]).unwrap())
+}
+
+#[test]
+fn test_env_overrides() {
+    let conf = load_str(
+        "port = 5001\nhost = \"127.0.0.1\"\n[servers.main]\nhost = \"127.0.0.1\"\nport = 8000\nname = \"main\"",
+        &[("KLEIN_PORT", "6000"), ("KLEIN_SERVERS__MAIN__HOST", "10.0.0.2"), ("KLEIN_SERVERS__EXTRA__HOST", "10.0.0.3"),
+            ("KLEIN_SERVERS__EXTRA__PORT", "8001"), ("KLEIN_SERVERS__EXTRA__NAME", "extra"), ("KLEIN_CONFIG", "ignored.toml")],
+    ).unwrap();
+    assert_eq!(conf.port, 6000);
+    assert_eq!(conf.servers["main"].host, "10.0.0.2");
+    assert_eq!(conf.servers["extra"].port, 8001);
+}
+
+#[test]
+fn test_config_errors() {
+    let base = "port = 5001\nhost = \"127.0.0.1\"\n";
+
+    let err = load_str(&format!("{base}[servers.main]\nhost = \"h\"\nprot = 8000\nname = \"main\""), &[]).unwrap_err();
+    assert!(matches!(err, ConfigError::UnknownField { ref key, .. } if key == "servers.main.prot"), "{err}");
+
+    let err = load_str(&format!("{base}[servers.main]\nhost = \"h\"\nport = 0\nname = \"main\""), &[]).unwrap_err();
+    assert!(matches!(err, ConfigError::Invalid { ref key, .. } if key == "servers.main.port"), "{err}");
+
+    let err = load_str(base, &[("KLEIN_PORT", "70000")]).unwrap_err();
+    assert!(matches!(err, ConfigError::Invalid { ref key, .. } if key == "port"), "{err}");
+
+    let err = load_str(base, &[("KLEIN_VERBOSE", "true")]).unwrap_err();
+    assert!(matches!(err, ConfigError::UnknownField { ref key, .. } if key == "verbose"), "{err}");
+
+    let err = load_str(&format!("{base}[servers.main]\nhost = \"h\"\nport = 1\nname = \"main\"\nweight = 1001"), &[]).unwrap_err();
+    assert!(matches!(err, ConfigError::Invalid { ref key, .. } if key == "servers.main.weight"), "{err}");
+
+    let err = load_str(&format!("{base}[servers.a]\nhost = \"h\"\nport = 1\nname = \"x\"\n[servers.b]\nhost = \"h\"\nport = 2\nname = \"x\""), &[]).unwrap_err();
+    assert!(matches!(err, ConfigError::DuplicateServer { ref name, .. } if name == "x"), "{err}");
+
+    let servers = "[servers.a]\nhost = \"h\"\nport = 1\nname = \"a\"\n[servers.b]\nhost = \"h\"\nport = 2\nname = \"b\"\n";
+    let err = load_str(&format!("{base}{servers}[ring]\nslots = 16\nvirtual_nodes = 10"), &[]).unwrap_err();
+    assert!(matches!(err, ConfigError::Invalid { ref key, .. } if key == "ring.slots"), "{err}");
+    assert!(load_str(&format!("{base}{servers}[ring]\nslots = 20\nvirtual_nodes = 10"), &[]).is_ok());
+    let err = load_str(&format!("{base}{servers}weight = 2\n[ring]\nslots = 20\nvirtual_nodes = 10"), &[]).unwrap_err();
+    assert!(matches!(err, ConfigError::Invalid { ref key, .. } if key == "ring.slots"), "{err}");
+
+    let err = load_str(&format!("{base}[[routes]]\nprefix = \"/neo\"\nstrategy = \"fastest\""), &[]).unwrap_err();
+    assert!(matches!(err, ConfigError::Invalid { ref key, .. } if key == "routes[0].strategy"), "{err}");
+
+    let err = load_str(&format!("{base}[[routes]]\nprefix = \"neo\"\nstrategy = \"p2c\""), &[]).unwrap_err();
+    assert!(matches!(err, ConfigError::Invalid { ref key, .. } if key == "routes[0].prefix"), "{err}");
+}
@@ -1,139 +1,562 @@
-use std::collections::BTreeMap;
-use std::sync::{Arc, RwLock};
+use std::collections::{BTreeMap, HashSet};
+use std::sync::Arc;
+use log::warn;
 use nanorand::{Rng, WyRand};
-use crate::config::SingleServer;
+use crate::config::{RingConf, SingleServer};
+use crate::hashers::{ring_hasher, RingHasher};
+use crate::placement::{new_placement, Placement};
+use crate::prometheus_stats::BOUNDED_LOAD_FORWARDS;
 
-const NUM_SERVER_CONTAINERS: usize = 3; // N
-const TOTAL_SLOTS: usize = 512; // #slots
-const VIRTUAL_SERVERS_PER_CONTAINER: usize = 9; // K
+// VirtualServer represents a virtual server in the consistent hash map
+#[derive(Clone, Debug)]
+pub struct VirtualServer {
+    pub server_container: SingleServer,
+    pub slot: usize,
+}
 
-// Hash function for request mapping
-fn hash_request(req_id: usize, total_slots: usize) -> usize {
-    (req_id + 2 * req_id + 17) % total_slots
+// SlotRing maps slots on a ring to virtual servers, a key belongs to the
+// first virtual server at or after the slot it hashes to
+pub struct SlotRing {
+    hash_map: BTreeMap<usize, VirtualServer>,
+    // Names of the servers that have virtual servers on the ring
+    servers: HashSet<String>,
+    slots: usize,
+    virtual_nodes: usize,
+    hasher: Arc<dyn RingHasher>,
 }
 
-// Hash function for virtual server mapping
-fn hash_virtual_server(container_id: usize, vs_index: usize, total_slots: usize) -> usize {
-    ((37 * container_id * vs_index) ^ (container_id | vs_index)) % total_slots
+impl SlotRing {
+    pub fn new(ring: &RingConf) -> SlotRing {
+        SlotRing {
+            hash_map: BTreeMap::new(),
+            servers: HashSet::new(),
+            slots: ring.slots,
+            virtual_nodes: ring.virtual_nodes,
+            hasher: ring_hasher(ring),
+        }
+    }
+
+    // Slot a request key lands on
+    fn request_slot(&self, key: &[u8]) -> usize {
+        (self.hasher.hash(key) % self.slots as u64) as usize
+    }
+
+    // Slot of the `vs_index`th virtual server of a container
+    //
+    // This only depends on the name so a server keeps its slots across restarts and reloads
+    fn virtual_server_slot(&self, container: &SingleServer, vs_index: usize) -> usize {
+        let mut key = Vec::with_capacity(container.name.len() + 9);
+        key.extend_from_slice(container.name.as_bytes());
+        key.push(b'#');
+        key.extend_from_slice(&(vs_index as u64).to_le_bytes());
+
+        (self.hasher.hash(&key) % self.slots as u64) as usize
+    }
+
+    // Return the list of virtual servers in the consistent hash map
+    #[allow(dead_code)]
+    pub fn virtual_servers(&self) -> Vec<VirtualServer> {
+        let mut vs_list: Vec<VirtualServer> = self.hash_map.values().cloned().collect();
+
+        // Sort by slot for easy visualization and debugging
+        vs_list.sort_by_key(|vs| vs.slot);
+
+        vs_list
+    }
 }
 
+impl Placement for SlotRing {
+    // Place the virtual servers of a single server container on the ring
+    //
+    // Every container gets `virtual_nodes * weight` slots, that is the number of slots a mapping
+    // of virtual server to physical server exists for each physical server
+    fn add(&mut self, container: &SingleServer) {
+        let total_slots = self.slots;
 
-// VirtualServer represents a virtual server in the consistent hash map
-#[derive(Clone, Debug)]
-pub struct VirtualServer {
-    pub server_container: SingleServer,
-    pub slot: usize,
+        for i in 0..self.virtual_nodes.saturating_mul(container.weight.max(1)) {
+            if self.hash_map.len() >= total_slots {
+                warn!("Hash ring is full ({} slots), {} only got {} virtual servers", total_slots, container.name, i);
+                return;
+            }
+            //  hash the server to get the slot
+            let mut slot = self.virtual_server_slot(container, i);
+
+            // Apply linear probing if there's a conflict
+            while self.hash_map.contains_key(&slot) {
+                slot = (slot + 1) % total_slots;
+            }
+
+            self.hash_map.insert(
+                slot,
+                VirtualServer {
+                    server_container: container.clone(),
+                    slot,
+                },
+            );
+            if i == 0 {
+                self.servers.insert(container.name.clone());
+            }
+        }
+    }
+
+    // Other virtual servers stay where they are, so only keys that were
+    // owned by the removed server move
+    fn remove(&mut self, name: &str) {
+        self.hash_map.retain(|_, vs| vs.server_container.name != name);
+        self.servers.remove(name);
+    }
+
+    // Walk the ring clockwise starting at the slot of the key, skipping virtual servers of
+    // servers that were already seen and stopping once every server was
+    fn candidates<'a>(&'a self, key: &[u8]) -> Box<dyn Iterator<Item=&'a SingleServer> + 'a> {
+        let slot = self.request_slot(key);
+        let mut seen = HashSet::with_capacity(self.servers.len());
+
+        Box::new(self.hash_map
+            .range(slot..)
+            .chain(self.hash_map.range(..slot))
+            .map(|(_, vs)| &vs.server_container)
+            .filter(move |c| seen.insert(c.name.as_str()))
+            .take(self.servers.len()))
+    }
+}
+
+// A server can be selected unless it is excluded, failing its health checks, ejected as
+// an outlier or its circuit breaker is open
+fn is_eligible(server: &SingleServer, exclude: &[String]) -> bool {
+    !exclude.contains(&server.name)
+        && server.state.health.is_healthy()
+        && !server.state.outlier.is_ejected(&server.name)
+        && server.state.breaker.allows_requests(&server.name)
 }
 
-// ServerPool maintains a consistent hash map and manages server containers
-#[derive(Clone)]
+// ServerPool manages server containers and routes keys to them using a placement algorithm
 pub struct ServerPool {
     servers: Vec<SingleServer>,
-    hash_map: BTreeMap<usize, VirtualServer>,
+    placement: Box<dyn Placement>,
     num_containers: usize,
+    ring: RingConf,
     pub rang_gen: WyRand,
 }
 
 impl ServerPool {
-    // Create a new server pool with the specified number of server containers and slots
-    pub fn new(num_containers: usize) -> ServerPool {
+    // Create a new empty server pool using the given ring parameters
+    pub fn new(ring: RingConf) -> ServerPool {
         ServerPool {
-            servers: Vec::with_capacity(num_containers),
-            hash_map: BTreeMap::new(),
-            num_containers,
+            servers: vec![],
+            placement: new_placement(&ring),
+            num_containers: 0,
+            ring,
             rang_gen: nanorand::rand::WyRand::new_seed(32422312),
         }
     }
 
-    // Initialize the server pool with server containers and virtual servers
+    // Ring parameters this pool was built with
+    pub fn ring(&self) -> &RingConf {
+        &self.ring
+    }
+
+    // Initialize the placement with all server containers
     pub fn initialize(&mut self) {
-        if !self.servers.is_empty() {
-            // Create virtual servers for each server container
-            let virtual_servers_per_container = TOTAL_SLOTS / self.servers.len();
-
-            // virtual_servers_per_container is the number of times a single server will
-            // be duplicated in our slot map, aka number of slots a mapping of virtual server
-            // to physical server exist for each physical server
-            for i in 0..virtual_servers_per_container {
-                for container in &self.servers {
-                    //  hash the server to get the slot
-                    let mut slot = hash_virtual_server(container.id, i, TOTAL_SLOTS);
-
-                    // Apply linear probing if there's a conflict
-                    while self.hash_map.contains_key(&slot) {
-                        slot = (slot + 1) % TOTAL_SLOTS;
-
-                        if self.hash_map.len() >=TOTAL_SLOTS{
-                            break;
-                        }
-                    }
-
-                    self.hash_map.insert(
-                        slot,
-                        VirtualServer {
-                            server_container: container.clone(),
-                            slot,
-                        },
-                    );
-                }
-            }
+        self.placement = new_placement(&self.ring);
+
+        for container in &self.servers {
+            self.placement.add(container);
         }
     }
 
-    // Retrieve the server container for a given request ID based on consistent hashing
-    pub fn get_server_container(&self, req_id: usize) -> Option<SingleServer> {
-        let slot = hash_request(req_id, TOTAL_SLOTS);
-        let hash_map = &self.hash_map;
+    // Retrieve the server container for a given request key
+    //
+    // This is the first candidate of the placement algorithm, for the ring the first
+    // virtual server at or after the slot. With bounded loads enabled servers that are
+    // at capacity are walked past
+    pub fn get_server_container(&self, key: &[u8], exclude: &[String]) -> Option<SingleServer> {
+        let mut walk = self.placement.candidates(key).filter(|c| is_eligible(c, exclude));
 
-        // Direct match
-        if let Some(vs) = hash_map.get(&slot) {
-            return Some(vs.server_container.clone());
-        }
+        let owner = walk.next()?;
+        let Some(epsilon) = self.ring.load_epsilon else {
+            return Some(owner.clone());
+        };
+        // shares are of the servers that can take the request, the others' load is not ours to spread
+        let eligible = self.eligible(exclude);
+        let total_in_flight: usize = eligible.iter().map(|c| c.state.in_flight()).sum();
+        let total_weight = eligible.iter().fold(0usize, |sum, c| sum.saturating_add(c.weight.max(1)));
 
-        // Linear probing to find the nearest slot with a virtual server
-        for i in 1..TOTAL_SLOTS {
-            let check_slot = (slot + i) % TOTAL_SLOTS;
-            if let Some(vs) = hash_map.get(&check_slot) {
-                return Some(vs.server_container.clone());
-            }
+        // a server may take this request if it stays within (1 + epsilon) times its share,
+        // the shares add up to more than the total so some server always has room
+        let has_room = |c: &SingleServer| {
+            let share = (total_in_flight + 1) as f64 * c.weight.max(1) as f64 / total_weight as f64;
+            (c.state.in_flight() + 1) as f64 <= ((1.0 + epsilon) * share).ceil()
+        };
+        if has_room(owner) {
+            return Some(owner.clone());
         }
+        BOUNDED_LOAD_FORWARDS.with_label_values(&[owner.name.as_str()]).inc();
 
-        None
+        walk.find(|c| has_room(c))
+            .or(Some(owner))
+            .cloned()
     }
-    pub fn add_server(&mut self, name: String, host: String, port: u16) {
-        self.num_containers += 1;
-        // Create server containers
-
-        // generate random numbers
-        self.servers.push(SingleServer {
-            id: self.rang_gen.generate_range(100_000..999_999),
+    pub fn add_server(&mut self, name: String, host: String, port: u16, weight: usize) {
+        self.insert_server(SingleServer {
+            id: 0,
             name,
             host,
             port,
+            weight,
+            pool: Default::default(),
+            timeouts: Default::default(),
+            state: Default::default(),
         });
-        self.initialize();
+    }
+
+    // Add a server container with all its settings, it is given a new id
+    pub fn insert_server(&mut self, mut server: SingleServer) {
+        self.num_containers += 1;
+
+        // generate random numbers
+        server.id = self.rang_gen.generate_range(100_000..999_999);
+        self.servers.push(server);
+        self.placement.add(self.servers.last().unwrap());
+    }
+
+    // Remove a server container from the pool
+    //
+    // For the ring only requests that were routed to the removed server move
+    pub fn remove_server(&mut self, name: &str) -> Option<SingleServer> {
+        let position = self.servers.iter().position(|c| c.name == name)?;
+        let removed = self.servers.remove(position);
+        self.num_containers -= 1;
+        self.placement.remove(name);
+
+        Some(removed)
+    }
+
+    // Change the weight of a server, for the ring only its own virtual servers are moved
+    //
+    // Returns the previous weight or None if there is no server with that name
+    pub fn set_weight(&mut self, name: &str, weight: usize) -> Option<usize> {
+        let position = self.servers.iter().position(|c| c.name == name)?;
+        let previous = std::mem::replace(&mut self.servers[position].weight, weight);
+
+        if previous != weight {
+            self.placement.remove(name);
+            self.placement.add(&self.servers[position]);
+        }
+        Some(previous)
+    }
+
+    // Build a fresh pool containing `servers`, servers that already have an id keep it
+    pub fn with_servers(&self, ring: RingConf, servers: Vec<SingleServer>) -> ServerPool {
+        let mut pool = ServerPool {
+            servers: Vec::with_capacity(servers.len()),
+            placement: new_placement(&ring),
+            num_containers: 0,
+            ring,
+            rang_gen: self.rang_gen.clone(),
+        };
+        for mut server in servers {
+            if server.id == 0 {
+                server.id = pool.rang_gen.generate_range(100_000..999_999);
+            }
+            pool.num_containers += 1;
+            pool.servers.push(server);
+        }
+        pool.initialize();
+        pool
+    }
+
+    // Servers a request may be sent to, all but the excluded, unhealthy and ejected ones
+    // and those whose circuit breaker is open
+    pub fn eligible(&self, exclude: &[String]) -> Vec<&SingleServer> {
+        self.servers.iter().filter(|c| is_eligible(c, exclude)).collect()
+    }
+
+    // Server containers managed by the pool, without cloning them
+    pub fn servers(&self) -> &[SingleServer] {
+        &self.servers
+    }
+
+    // Whether servers with a total weight of `weight` can still be added without running
+    // out of virtual servers, see RingConf::capacity
+    pub fn fits(&self, weight: usize) -> bool {
+        let total_weight = self.servers.iter().fold(0usize, |sum, c| sum.saturating_add(c.weight.max(1)));
+        total_weight.saturating_add(weight) <= self.ring.capacity()
     }
 
     // Return the list of server containers managed by the pool
     pub fn server_containers(&self) -> Vec<SingleServer> {
         self.servers.clone()
     }
+}
 
-    // Return the list of virtual servers in the consistent hash map
-    pub fn virtual_servers(&self) -> Vec<VirtualServer> {
-        let mut vs_list: Vec<VirtualServer> = self.hash_map.values().cloned().collect();
 
-        // Sort by slot for easy visualization and debugging
-        vs_list.sort_by_key(|vs| vs.slot);
+#[cfg(test)]
+pub fn test_pool(ring: RingConf, num_servers: usize) -> ServerPool {
+    let mut pool = ServerPool::new(ring);
+    for i in 0..num_servers {
+        pool.add_server(format!("server-{i}"), "127.0.0.1".to_string(), 8000 + i as u16, 1);
+    }
+    pool
+}
 
-        vs_list
+#[cfg(test)]
+fn test_slot_ring(ring: &RingConf, num_servers: usize) -> SlotRing {
+    let mut slot_ring = SlotRing::new(ring);
+    for server in test_pool(ring.clone(), num_servers).server_containers() {
+        slot_ring.add(&server);
     }
+    slot_ring
 }
 
+// Number of slots owned by each server, a virtual server owns every slot
+// between the previous virtual server (exclusive) and itself (inclusive)
+#[cfg(test)]
+fn slot_shares(slot_ring: &SlotRing) -> std::collections::HashMap<String, usize> {
+    let mut shares = std::collections::HashMap::new();
+    let vs = slot_ring.virtual_servers();
+    let slots = slot_ring.slots;
+
+    for (i, v) in vs.iter().enumerate() {
+        let owned = if i == 0 {
+            v.slot + slots - vs.last().unwrap().slot
+        } else {
+            v.slot - vs[i - 1].slot
+        };
+        *shares.entry(v.server_container.name.clone()).or_insert(0) += owned;
+    }
+    shares
+}
+
+// Assert that no server's share of the ring deviates from the
+// ideal share by more than `tolerance` (relative), for 1 to 100 servers
+#[cfg(test)]
+fn assert_distribution(ring: RingConf, tolerance: f64) {
+    for num_servers in 1..=100 {
+        let shares = slot_shares(&test_slot_ring(&ring, num_servers));
+        assert_eq!(shares.len(), num_servers);
+        assert_eq!(shares.values().sum::<usize>(), ring.slots);
+
+        let ideal = ring.slots as f64 / num_servers as f64;
+        for (name, share) in shares {
+            let deviation = (share as f64 - ideal).abs() / ideal;
+            assert!(deviation <= tolerance,
+                "{} servers: {} owns {} slots, ideal is {:.1} (deviation {:.2} > {:.2})",
+                num_servers, name, share, ideal, deviation, tolerance);
+        }
+    }
+}
 
 #[test]
 fn test_out() {
-    let mut containers = ServerPool::new(3);
-    containers.initialize();
+    let containers = test_slot_ring(&RingConf::default(), 3);
     containers.virtual_servers().iter().for_each(|c| println!("slot={} name={}", c.slot, &c.server_container.name));
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+const ALL_HASHERS: [crate::hashers::HasherKind; 3] = [
+    crate::hashers::HasherKind::Xxhash64,
+    crate::hashers::HasherKind::Murmur3,
+    crate::hashers::HasherKind::Siphash,
+];
+
+#[cfg(test)]
+fn test_ring(hasher: crate::hashers::HasherKind, slots: usize, virtual_nodes: usize) -> RingConf {
+    RingConf { slots, virtual_nodes, hasher, seed: 7, key: 11, ..RingConf::default() }
+}
+
+// Server each of `num_keys` request keys is routed to
+#[cfg(test)]
+fn key_owners(pool: &ServerPool, num_keys: usize) -> Vec<String> {
+    (0..num_keys as u64).map(|c| pool.get_server_container(&c.to_le_bytes(), &[]).unwrap().name).collect()
+}
+
+#[test]
+fn test_ring_parameters() {
+    let slot_ring = test_slot_ring(&RingConf { slots: 64, virtual_nodes: 4, ..RingConf::default() }, 3);
+    assert_eq!(slot_ring.virtual_servers().len(), 12);
+    assert!(slot_ring.virtual_servers().iter().all(|c| c.slot < 64));
+
+    // a full ring stops placing virtual servers instead of looping forever
+    let slot_ring = test_slot_ring(&RingConf { slots: 8, virtual_nodes: 4, ..RingConf::default() }, 3);
+    assert_eq!(slot_ring.virtual_servers().len(), 8);
+}
+
+// Largest deviation from the ideal share allowed by the distribution tests,
+// `RING_TOLERANCE` in the environment overrides it
+#[cfg(test)]
+fn ring_tolerance() -> f64 {
+    std::env::var("RING_TOLERANCE").ok().and_then(|c| c.parse().ok()).unwrap_or(0.35)
+}
+
+#[test]
+fn test_load_distribution() {
+    for hasher in ALL_HASHERS {
+        assert_distribution(test_ring(hasher, 1 << 20, 200), ring_tolerance());
+    }
+}
+
+// The default ring spreads 1 to 100 servers evenly
+#[test]
+fn test_default_distribution() {
+    for hasher in ALL_HASHERS {
+        assert_distribution(RingConf { hasher, ..RingConf::default() }, ring_tolerance());
+    }
+}
+
+// Hash keys into buckets and check the counts with a chi-squared test
+#[test]
+fn test_slot_spread() {
+    const BUCKETS: usize = 64;
+    const KEYS: usize = 64_000;
+    // critical value of the chi-squared distribution with 63 degrees of freedom at p = 0.001
+    const CRITICAL: f64 = 103.4;
+
+    for hasher in ALL_HASHERS {
+        let hasher = crate::hashers::ring_hasher(&test_ring(hasher, BUCKETS, 1));
+        // sequential keys are the worst case for weak hash functions
+        for keys in [
+            (0..KEYS).map(|c| (c as u64).to_le_bytes().to_vec()).collect::<Vec<_>>(),
+            (0..KEYS).map(|c| format!("server-{c}#0").into_bytes()).collect::<Vec<_>>(),
+        ] {
+            let mut counts = [0usize; BUCKETS];
+            for key in keys {
+                counts[(hasher.hash(&key) % BUCKETS as u64) as usize] += 1;
+            }
+            let expected = (KEYS / BUCKETS) as f64;
+            let chi_squared: f64 = counts.iter().map(|c| (*c as f64 - expected).powi(2) / expected).sum();
+            assert!(chi_squared < CRITICAL, "chi squared {:.1} >= {}", chi_squared, CRITICAL);
+        }
+    }
+}
+
+// Adding a server only moves keys to the new server, and roughly 1/(n+1) of them
+#[test]
+fn test_key_movement_on_add() {
+    const KEYS: usize = 20_000;
+
+    for hasher in ALL_HASHERS {
+        for num_servers in [1, 5, 20] {
+            let mut pool = test_pool(test_ring(hasher, 1 << 32, 100), num_servers);
+            let before = key_owners(&pool, KEYS);
+            pool.add_server("new".to_string(), "127.0.0.1".to_string(), 9000, 1);
+            let after = key_owners(&pool, KEYS);
+
+            let moved: Vec<_> = before.iter().zip(&after).filter(|(b, a)| b != a).collect();
+            assert!(moved.iter().all(|(_, a)| a.as_str() == "new"), "keys moved between old servers");
+
+            let fraction = moved.len() as f64 / KEYS as f64;
+            let ideal = 1.0 / (num_servers + 1) as f64;
+            assert!((fraction - ideal).abs() < ideal * 0.35, "{:?}: moved {:.3} of keys, ideal {:.3}", hasher, fraction, ideal);
+        }
+    }
+}
+
+// Removing a server only moves the keys it owned
+#[test]
+fn test_key_movement_on_remove() {
+    const KEYS: usize = 20_000;
+
+    for hasher in ALL_HASHERS {
+        let mut pool = test_pool(test_ring(hasher, 1 << 20, 100), 10);
+        let before = key_owners(&pool, KEYS);
+
+        assert!(pool.remove_server("server-3").is_some());
+        assert!(pool.remove_server("server-3").is_none());
+        assert_eq!(pool.server_containers().len(), 9);
+
+        let after = key_owners(&pool, KEYS);
+        for (b, a) in before.iter().zip(&after) {
+            if b != a {
+                assert_eq!(b, "server-3", "{:?}: a key moved from {} to {}", hasher, b, a);
+            }
+        }
+        assert!(after.iter().all(|c| c != "server-3"));
+        assert!(before.iter().any(|c| c == "server-3"));
+    }
+}
+
+#[test]
+fn test_weights() {
+    const KEYS: usize = 40_000;
+    let mut pool = test_pool(test_ring(crate::hashers::HasherKind::Xxhash64, 1 << 20, 100), 4);
+
+    // server-0 goes from 1/4 to 3/6 of the keys
+    let before = key_owners(&pool, KEYS);
+    assert_eq!(pool.set_weight("server-0", 3), Some(1));
+    assert_eq!(pool.set_weight("missing", 3), None);
+
+    let after = key_owners(&pool, KEYS);
+    let share = after.iter().filter(|c| c.as_str() == "server-0").count() as f64 / KEYS as f64;
+    assert!((share - 0.5).abs() < 0.1, "server-0 has {:.3} of the keys", share);
+
+    // keys only moved to the re-weighted server
+    for (b, a) in before.iter().zip(&after) {
+        if b != a {
+            assert_eq!(a, "server-0", "a key moved from {} to {}", b, a);
+        }
+    }
+}
+
+// Heavy servers on the default ring both keep their share, and weight that would
+// not fit is refused up front
+#[test]
+fn test_heavy_weights() {
+    const KEYS: usize = 20_000;
+    let mut pool = ServerPool::new(RingConf::default());
+    pool.add_server("big".to_string(), "127.0.0.1".to_string(), 8000, 150);
+    pool.add_server("large".to_string(), "127.0.0.1".to_string(), 8001, 150);
+    pool.add_server("small".to_string(), "127.0.0.1".to_string(), 8002, 1);
+
+    let owners = key_owners(&pool, KEYS);
+    for name in ["big", "large"] {
+        let share = owners.iter().filter(|c| c.as_str() == name).count() as f64 / KEYS as f64;
+        assert!((share - 150.0 / 301.0).abs() < 0.05, "{name} has {share:.3} of the keys");
+    }
+    assert!(owners.iter().any(|c| c == "small"));
+
+    let capacity = RingConf::default().capacity();
+    assert!(pool.fits(capacity - 301));
+    assert!(!pool.fits(capacity - 300));
+}
+
+#[test]
+fn test_bounded_loads() {
+    let ring = RingConf { load_epsilon: Some(0.25), ..test_ring(crate::hashers::HasherKind::Xxhash64, 1 << 20, 100) };
+    let pool = test_pool(ring, 4);
+    let key = 42u64.to_le_bytes();
+    let owner = pool.get_server_container(&key, &[]).unwrap();
+
+    // hold requests against the owner of the key until it is over capacity
+    let mut held = vec![];
+    while pool.get_server_container(&key, &[]).unwrap().name == owner.name {
+        held.push(owner.state.start_request(&owner.name));
+        assert!(held.len() < 10, "owner was never considered loaded");
+    }
+    // with 1 request in flight the cap is ceil(1.25 * 2 / 4) = 1
+    assert_eq!(held.len(), 1);
+    let other = pool.get_server_container(&key, &[]).unwrap();
+    assert_ne!(other.name, owner.name);
+
+    // and it comes back once its requests finish
+    held.clear();
+    assert_eq!(pool.get_server_container(&key, &[]).unwrap().name, owner.name);
+    assert_eq!(owner.state.in_flight(), 0);
+
+    // every server is loaded evenly under a hot key
+    let mut held = vec![];
+    for _ in 0..40 {
+        let server = pool.get_server_container(&key, &[]).unwrap();
+        held.push(server.state.start_request(&server.name));
+    }
+    for server in pool.server_containers() {
+        assert!(server.state.in_flight() <= 13, "{} has {} requests", server.name, server.state.in_flight());
+    }
+    drop(held);
+
+    // shares only count servers that can take the request, with half the pool excluded the
+    // cap with 1 request in flight is ceil(1.25 * 2 / 2) = 2
+    let exclude: Vec<String> = pool.servers().iter().map(|c| c.name.clone()).filter(|c| *c != owner.name).take(2).collect();
+    let _held = owner.state.start_request(&owner.name);
+    assert_eq!(pool.get_server_container(&key, &exclude).unwrap().name, owner.name);
+}
@@ -0,0 +1,263 @@
+use std::net::{IpAddr, SocketAddr};
+use axum::extract::ConnectInfo;
+use axum::http::header::{FORWARDED, HOST, VIA};
+use axum::http::{HeaderMap, HeaderName, HeaderValue, Request, Version};
+use serde::Deserialize;
+
+const X_FORWARDED_FOR: HeaderName = HeaderName::from_static("x-forwarded-for");
+const X_FORWARDED_PROTO: HeaderName = HeaderName::from_static("x-forwarded-proto");
+const X_FORWARDED_HOST: HeaderName = HeaderName::from_static("x-forwarded-host");
+
+/// An address or CIDR block, written in the config as `10.0.0.7`, `10.0.0.0/8` or `fd00::/8`
+#[derive(Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(try_from = "String")]
+pub struct IpRange {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+impl TryFrom<String> for IpRange {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        let (addr, prefix_len) = match value.split_once('/') {
+            Some((addr, len)) => (addr, Some(len)),
+            None => (value.as_str(), None),
+        };
+        let addr: IpAddr = addr.trim().parse().map_err(|_| format!("invalid address `{value}`"))?;
+        let max_len = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix_len {
+            Some(len) => len.trim().parse().ok()
+                .filter(|c| *c <= max_len)
+                .ok_or_else(|| format!("invalid prefix length in `{value}`, expected 0 to {max_len}"))?,
+            None => max_len,
+        };
+        Ok(IpRange { addr, prefix_len })
+    }
+}
+
+impl IpRange {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        let mask = |bits: u32| if self.prefix_len == 0 { 0 } else { u128::MAX << (bits - self.prefix_len as u32) };
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(range), IpAddr::V4(ip)) => (u32::from(range) ^ u32::from(ip)) as u128 & mask(32) == 0,
+            (IpAddr::V6(range), IpAddr::V6(ip)) => (u128::from(range) ^ u128::from(ip)) & mask(128) == 0,
+            _ => false,
+        }
+    }
+}
+
+fn default_via() -> String {
+    "klein".to_string()
+}
+
+/// Forwarding headers added to proxied requests
+#[derive(Deserialize)]
+#[derive(Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ForwardingConf {
+    /// Proxies in front of klein, the forwarding headers they send are kept and appended to.
+    /// Those headers are dropped when sent by anyone else, as clients could forge them
+    #[serde(default)]
+    pub trusted_proxies: Vec<IpRange>,
+    /// Name of this klein instance in `Via`
+    #[serde(default = "default_via")]
+    pub via: String,
+}
+
+impl Default for ForwardingConf {
+    fn default() -> Self {
+        ForwardingConf { trusted_proxies: vec![], via: default_via() }
+    }
+}
+
+impl ForwardingConf {
+    fn is_trusted(&self, ip: IpAddr) -> bool {
+        self.trusted_proxies.iter().any(|c| c.contains(ip))
+    }
+}
+
+/// Address of the connected peer
+fn peer_ip<B>(req: &Request<B>) -> Option<IpAddr> {
+    req.extensions().get::<ConnectInfo<SocketAddr>>().map(|c| c.0.ip().to_canonical())
+}
+
+/// Address of the client that sent the request
+///
+/// Behind trusted proxies this is the last `X-Forwarded-For` entry that is not a trusted
+/// proxy itself, entries before it could have been made up by the client. Anyone else's
+/// `X-Forwarded-For` is ignored and the peer address is used
+pub fn client_ip<B>(conf: &ForwardingConf, req: &Request<B>) -> Option<IpAddr> {
+    let mut client = peer_ip(req)?;
+    if !conf.is_trusted(client) {
+        return Some(client);
+    }
+    let entries: Vec<&str> = req.headers()
+        .get_all(X_FORWARDED_FOR)
+        .iter()
+        .filter_map(|c| c.to_str().ok())
+        .flat_map(|c| c.split(','))
+        .collect();
+    for entry in entries.into_iter().rev() {
+        // an entry that is not an address cannot be vouched for, neither can anything before it
+        let Ok(ip) = entry.trim().parse::<IpAddr>() else {
+            break;
+        };
+        client = ip.to_canonical();
+        if !conf.is_trusted(client) {
+            break;
+        }
+    }
+    Some(client)
+}
+
+/// Append `value` to a comma separated header, merging all existing lines into one
+fn append(headers: &mut HeaderMap, name: HeaderName, value: &str) {
+    let mut combined: Vec<u8> = vec![];
+    for existing in headers.get_all(&name) {
+        combined.extend_from_slice(existing.as_bytes());
+        combined.extend_from_slice(b", ");
+    }
+    combined.extend_from_slice(value.as_bytes());
+
+    if let Ok(value) = HeaderValue::from_bytes(&combined) {
+        headers.insert(name, value);
+    }
+}
+
+/// A `Forwarded` node, IPv6 addresses have to be bracketed and quoted
+fn forwarded_node(ip: IpAddr) -> String {
+    match ip {
+        IpAddr::V4(ip) => ip.to_string(),
+        IpAddr::V6(ip) => format!("\"[{ip}]\""),
+    }
+}
+
+fn via_protocol(version: Version) -> &'static str {
+    match version {
+        Version::HTTP_09 => "0.9",
+        Version::HTTP_10 => "1.0",
+        Version::HTTP_2 => "2",
+        Version::HTTP_3 => "3",
+        _ => "1.1",
+    }
+}
+
+/// Tell the backend who the request came from
+///
+/// Appends the client address to `X-Forwarded-For` and `Forwarded` (RFC 7239), sets
+/// `X-Forwarded-Proto` and `X-Forwarded-Host` unless a trusted proxy already did,
+/// and appends klein to `Via`. Must run before the `Host` header is replaced
+pub fn add_forwarding_headers<B>(conf: &ForwardingConf, req: &mut Request<B>) {
+    let peer = peer_ip(req);
+    let trusted = peer.is_some_and(|ip| conf.is_trusted(ip));
+    let version = req.version();
+    let headers = req.headers_mut();
+    let host = headers.get(HOST).cloned();
+
+    if !trusted {
+        for name in [X_FORWARDED_FOR, X_FORWARDED_PROTO, X_FORWARDED_HOST, FORWARDED] {
+            headers.remove(name);
+        }
+    }
+    // klein only listens on plain http
+    let mut forwarded = vec![];
+    if let Some(ip) = peer {
+        append(headers, X_FORWARDED_FOR, &ip.to_string());
+        forwarded.push(format!("for={}", forwarded_node(ip)));
+    }
+    if !headers.contains_key(X_FORWARDED_PROTO) {
+        headers.insert(X_FORWARDED_PROTO, HeaderValue::from_static("http"));
+    }
+    if let Some(host) = host {
+        if let Ok(value) = host.to_str() {
+            forwarded.push(format!("host=\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\"")));
+        }
+        if !headers.contains_key(X_FORWARDED_HOST) {
+            headers.insert(X_FORWARDED_HOST, host);
+        }
+    }
+    forwarded.push("proto=http".to_string());
+    append(headers, FORWARDED, &forwarded.join(";"));
+    append(headers, VIA, &format!("{} {}", via_protocol(version), conf.via));
+}
+
+#[test]
+fn test_ip_range() {
+    let range = |c: &str| IpRange::try_from(c.to_string());
+    let ip = |c: &str| c.parse::<IpAddr>().unwrap();
+
+    assert!(range("10.0.0.0/8").unwrap().contains(ip("10.200.3.4")));
+    assert!(!range("10.0.0.0/8").unwrap().contains(ip("11.0.0.1")));
+    assert!(range("10.0.0.7").unwrap().contains(ip("::ffff:10.0.0.7")));
+    assert!(!range("10.0.0.7").unwrap().contains(ip("10.0.0.8")));
+    assert!(range("fd00::/8").unwrap().contains(ip("fd12::1")));
+    assert!(!range("fd00::/8").unwrap().contains(ip("10.0.0.1")));
+    assert!(range("0.0.0.0/0").unwrap().contains(ip("8.8.8.8")));
+
+    assert!(range("10.0.0.0/33").is_err());
+    assert!(range("localhost").is_err());
+}
+
+#[test]
+fn test_forwarding_headers() {
+    let conf = ForwardingConf {
+        trusted_proxies: vec![IpRange::try_from("10.0.0.0/8".to_string()).unwrap()],
+        via: "klein-1".to_string(),
+    };
+    let request = |peer: &str| {
+        let mut req = Request::builder()
+            .uri("/neo/feed")
+            .header("host", "api.example.com")
+            .header("x-forwarded-for", "203.0.113.9")
+            .header("x-forwarded-proto", "https")
+            .header("forwarded", "for=203.0.113.9;proto=https")
+            .header("via", "1.1 edge")
+            .body(())
+            .unwrap();
+        req.extensions_mut().insert(ConnectInfo(SocketAddr::new(peer.parse().unwrap(), 4000)));
+        add_forwarding_headers(&conf, &mut req);
+        req
+    };
+
+    // a trusted proxy's headers are appended to
+    let req = request("10.1.2.3");
+    assert_eq!(req.headers()["x-forwarded-for"], "203.0.113.9, 10.1.2.3");
+    assert_eq!(req.headers()["x-forwarded-proto"], "https");
+    assert_eq!(req.headers()["x-forwarded-host"], "api.example.com");
+    assert_eq!(req.headers()["forwarded"], "for=203.0.113.9;proto=https, for=10.1.2.3;host=\"api.example.com\";proto=http");
+    assert_eq!(req.headers()["via"], "1.1 edge, 1.1 klein-1");
+
+    // anyone else's are replaced
+    let req = request("2001:db8::7");
+    assert_eq!(req.headers()["x-forwarded-for"], "2001:db8::7");
+    assert_eq!(req.headers()["x-forwarded-proto"], "http");
+    assert_eq!(req.headers()["forwarded"], "for=\"[2001:db8::7]\";host=\"api.example.com\";proto=http");
+    assert_eq!(req.headers()["via"], "1.1 edge, 1.1 klein-1");
+}
+
+// Trusted proxies pass the client address on, anyone else is the client
+#[test]
+fn test_client_ip() {
+    let conf = ForwardingConf {
+        trusted_proxies: vec![IpRange::try_from("10.0.0.0/8".to_string()).unwrap()],
+        ..Default::default()
+    };
+    let client = |peer: &str, forwarded_for: &[&str]| {
+        let mut req = Request::builder().uri("/neo");
+        for value in forwarded_for {
+            req = req.header("x-forwarded-for", *value);
+        }
+        let mut req = req.body(()).unwrap();
+        req.extensions_mut().insert(ConnectInfo(SocketAddr::new(peer.parse().unwrap(), 4000)));
+        client_ip(&conf, &req).unwrap().to_string()
+    };
+
+    assert_eq!(client("10.1.2.3", &["203.0.113.9"]), "203.0.113.9");
+    // proxies in front of the proxy are skipped, what the client claims before them is not taken
+    assert_eq!(client("10.1.2.3", &["192.0.2.1, 203.0.113.9", "10.4.5.6"]), "203.0.113.9");
+    assert_eq!(client("10.1.2.3", &["unknown, 10.4.5.6"]), "10.4.5.6");
+    assert_eq!(client("10.1.2.3", &[]), "10.1.2.3");
+    assert_eq!(client("198.51.100.4", &["203.0.113.9"]), "198.51.100.4");
+}
@@ -0,0 +1,72 @@
+use std::hash::Hasher;
+use std::io::Cursor;
+use std::sync::Arc;
+use serde::Deserialize;
+use siphasher::sip::SipHasher24;
+use twox_hash::XxHash64;
+use crate::config::RingConf;
+
+/// Hash function used to place virtual servers and requests on the ring
+pub trait RingHasher: Send + Sync {
+    fn hash(&self, bytes: &[u8]) -> u64;
+}
+
+/// Hash functions that can be selected with `ring.hasher`
+#[derive(Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum HasherKind {
+    #[default]
+    Xxhash64,
+    Murmur3,
+    Siphash,
+}
+
+/// xxHash64, seeded with `ring.seed`
+pub struct XxHash64Hasher {
+    seed: u64,
+}
+
+impl RingHasher for XxHash64Hasher {
+    fn hash(&self, bytes: &[u8]) -> u64 {
+        XxHash64::oneshot(self.seed, bytes)
+    }
+}
+
+/// The 64 bit half of MurmurHash3 x64_128, seeded with the low 32 bits of `ring.seed`
+pub struct Murmur3Hasher {
+    seed: u32,
+}
+
+impl RingHasher for Murmur3Hasher {
+    fn hash(&self, bytes: &[u8]) -> u64 {
+        // reading from an in memory cursor cannot fail
+        murmur3::murmur3_x64_128(&mut Cursor::new(bytes), self.seed).unwrap_or_default() as u64
+    }
+}
+
+/// SipHash-2-4 keyed with `ring.seed` and `ring.key`
+///
+/// Unlike the other hashers the output cannot be predicted without the key,
+/// so clients cannot craft request keys that all land on one server
+pub struct SipHasher {
+    k0: u64,
+    k1: u64,
+}
+
+impl RingHasher for SipHasher {
+    fn hash(&self, bytes: &[u8]) -> u64 {
+        let mut hasher = SipHasher24::new_with_keys(self.k0, self.k1);
+        hasher.write(bytes);
+        hasher.finish()
+    }
+}
+
+/// Create the hasher selected in the ring config
+pub fn ring_hasher(ring: &RingConf) -> Arc<dyn RingHasher> {
+    match ring.hasher {
+        HasherKind::Xxhash64 => Arc::new(XxHash64Hasher { seed: ring.seed }),
+        HasherKind::Murmur3 => Arc::new(Murmur3Hasher { seed: ring.seed as u32 }),
+        HasherKind::Siphash => Arc::new(SipHasher { k0: ring.seed, k1: ring.key }),
+    }
+}
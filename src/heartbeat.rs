@@ -1,13 +1,105 @@
-use std::sync::Arc;
-use std::sync::atomic::Ordering;
-use std::time::{Instant, UNIX_EPOCH};
-use axum::extract::State;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant, UNIX_EPOCH};
+use axum::body::Body;
+use axum::extract::{Request, State};
+use axum::http::Method;
 use axum::Json;
-use serde::Serialize;
+use log::{info, trace, warn};
+use serde::{Deserialize, Serialize};
 use crate::AppContext;
+use crate::config::SingleServer;
+use crate::prometheus_stats::BACKEND_HEALTHY;
 
-#[derive(Serialize, Debug, Default)]
-struct HeartBeatInfo {
+fn default_enabled() -> bool {
+    true
+}
+
+fn default_interval_ms() -> u64 {
+    5000
+}
+
+fn default_path() -> String {
+    "/heartbeat".to_string()
+}
+
+fn default_method() -> String {
+    "HEAD".to_string()
+}
+
+fn default_timeout_ms() -> u64 {
+    2000
+}
+
+fn default_healthy_threshold() -> usize {
+    2
+}
+
+fn default_unhealthy_threshold() -> usize {
+    3
+}
+
+/// Active health checks of every backend, set in `[health_check]`
+///
+/// Backends that fail `unhealthy_threshold` checks in a row are not selected until
+/// they pass `healthy_threshold` checks in a row. New backends start out healthy, except
+/// the replicas the supervisor starts
+#[derive(Deserialize)]
+#[derive(Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct HealthConf {
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+    /// Time between two rounds of checks
+    #[serde(default = "default_interval_ms")]
+    pub interval_ms: u64,
+    /// Path and method of the check request
+    #[serde(default = "default_path")]
+    pub path: String,
+    #[serde(default = "default_method")]
+    pub method: String,
+    /// Statuses of a passing check, any status below 400 passes when empty
+    #[serde(default)]
+    pub expected_status: Vec<u16>,
+    /// Checks that take longer fail
+    #[serde(default = "default_timeout_ms")]
+    pub timeout_ms: u64,
+    #[serde(default = "default_healthy_threshold")]
+    pub healthy_threshold: usize,
+    #[serde(default = "default_unhealthy_threshold")]
+    pub unhealthy_threshold: usize,
+}
+
+impl Default for HealthConf {
+    fn default() -> Self {
+        HealthConf {
+            enabled: default_enabled(),
+            interval_ms: default_interval_ms(),
+            path: default_path(),
+            method: default_method(),
+            expected_status: vec![],
+            timeout_ms: default_timeout_ms(),
+            healthy_threshold: default_healthy_threshold(),
+            unhealthy_threshold: default_unhealthy_threshold(),
+        }
+    }
+}
+
+impl HealthConf {
+    fn passes(&self, status: u16) -> bool {
+        if self.expected_status.is_empty() {
+            status < 400
+        } else {
+            self.expected_status.contains(&status)
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Default, Clone)]
+pub struct HeartBeatInfo {
+    /// Whether the backend is selected, decided by the last few checks
+    healthy: bool,
+    /// Whether the last check passed
     alive: bool,
     name: String,
     host: String,
@@ -16,50 +108,195 @@ struct HeartBeatInfo {
     status_text: Option<String>,
     time_taken_ms: u64,
     error: Option<String>,
+    /// Unix time of the check, 0 before the first one
+    checked_at: u64,
+}
+
+#[derive(Debug, Default)]
+struct HealthCounts {
+    /// Checks passed or failed in a row
+    successes: usize,
+    failures: usize,
+    last: Option<HeartBeatInfo>,
+}
+
+/// Health of a backend as seen by the background checks
+#[derive(Debug)]
+pub struct HealthState {
+    healthy: AtomicBool,
+    counts: Mutex<HealthCounts>,
+}
+
+impl Default for HealthState {
+    fn default() -> Self {
+        HealthState { healthy: AtomicBool::new(true), counts: Default::default() }
+    }
+}
+
+impl HealthState {
+    /// Whether the backend may be selected
+    pub fn is_healthy(&self) -> bool {
+        self.healthy.load(Ordering::Acquire)
+    }
+
+    /// Keep a backend that is still booting out of selection until it passes `healthy_threshold` checks
+    pub fn mark_starting(&self) {
+        self.healthy.store(false, Ordering::Release);
+    }
+
+    /// Record the outcome of a check, ejecting or re-admitting the backend once a threshold is reached
+    fn record(&self, conf: &HealthConf, info: HeartBeatInfo) {
+        let mut counts = self.counts.lock().unwrap();
+        if info.alive {
+            counts.successes += 1;
+            counts.failures = 0;
+        } else {
+            counts.failures += 1;
+            counts.successes = 0;
+        }
+        let healthy = self.is_healthy();
+        if healthy && counts.failures >= conf.unhealthy_threshold {
+            warn!("{} failed {} health checks in a row, ejecting it: {}", info.name, counts.failures,
+                info.error.as_deref().or(info.status_text.as_deref()).unwrap_or("unexpected status"));
+            self.healthy.store(false, Ordering::Release);
+        } else if !healthy && counts.successes >= conf.healthy_threshold {
+            info!("{} passed {} health checks in a row, re-admitting it", info.name, counts.successes);
+            self.healthy.store(true, Ordering::Release);
+        }
+        BACKEND_HEALTHY.with_label_values(&[info.name.as_str()]).set(if self.is_healthy() { 1.0 } else { 0.0 });
+        counts.last = Some(info);
+    }
+
+    // Record a check that passed or failed
+    #[cfg(test)]
+    pub fn record_check(&self, conf: &HealthConf, alive: bool, name: &str) {
+        self.record(conf, HeartBeatInfo { alive, name: name.to_string(), ..Default::default() });
+    }
+
+    /// The last check, or the backend's address when it has not been checked yet
+    fn report(&self, server: &SingleServer) -> HeartBeatInfo {
+        let counts = self.counts.lock().unwrap();
+        let mut info = counts.last.clone().unwrap_or_else(|| HeartBeatInfo {
+            name: server.name.clone(),
+            host: server.host.clone(),
+            port: server.port,
+            ..Default::default()
+        });
+        info.healthy = self.is_healthy();
+        info
+    }
+}
+
+fn unix_now() -> u64 {
+    std::time::SystemTime::now().duration_since(UNIX_EPOCH).expect("time went backwards").as_secs()
+}
+
+/// Send one check request to a backend
+async fn check_server(ctx: &AppContext, server: &SingleServer) -> HeartBeatInfo {
+    let conf = &ctx.app_config.health_check;
+    let mut info = HeartBeatInfo {
+        name: server.name.clone(),
+        host: server.host.clone(),
+        port: server.port,
+        checked_at: unix_now(),
+        ..Default::default()
+    };
+    // the method is checked when the config is loaded
+    let req = Request::builder()
+        .method(Method::from_bytes(conf.method.as_bytes()).unwrap_or(Method::HEAD))
+        .uri(format!("http://{}:{}{}", server.host, server.port, conf.path))
+        .body(Body::empty());
+    let req = match req {
+        Ok(req) => req,
+        Err(e) => {
+            info.error = Some(e.to_string());
+            return info;
+        }
+    };
+    let client = ctx.app_config.client(server);
+
+    let start = Instant::now();
+    match tokio::time::timeout(Duration::from_millis(conf.timeout_ms), client.request(req)).await {
+        Ok(Ok(response)) => {
+            let status = response.status();
+            info.status_code = Some(status.as_u16());
+            info.status_text = status.canonical_reason().map(str::to_string);
+            info.alive = conf.passes(status.as_u16());
+        }
+        Ok(Err(e)) => info.error = Some(e.to_string()),
+        Err(_) => info.error = Some(format!("no response within {}ms", conf.timeout_ms)),
+    }
+    info.time_taken_ms = start.elapsed().as_millis() as u64;
+    info
+}
+
+/// Check every backend at the configured interval
+///
+/// All backends are checked at the same time, a round ends when the slowest check does
+pub async fn health_checks(ctx: Arc<AppContext>) {
+    let conf = &ctx.app_config.health_check;
+    let mut interval = tokio::time::interval(Duration::from_millis(conf.interval_ms));
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+    loop {
+        interval.tick().await;
+        let servers = ctx.hash_server.read().unwrap().server_containers();
+        let checks: Vec<_> = servers.into_iter().map(|server| {
+            let ctx = ctx.clone();
+            tokio::spawn(async move {
+                let info = check_server(&ctx, &server).await;
+                trace!("Health check of {}: {:?}", server.name, info);
+                server.state.health.record(&ctx.app_config.health_check, info);
+            })
+        }).collect();
+        for check in checks {
+            let _ = check.await;
+        }
+        ctx.last_hb_time.store(unix_now(), Ordering::Release);
+    }
 }
 
 #[derive(Serialize)]
 pub struct HeartBeatResp {
     request_time: u64,
+    /// Unix time the last round of checks finished, 0 before the first one
+    last_check: u64,
     server_hb: Vec<HeartBeatInfo>,
 }
 
-pub async fn heartbeat(State(ctx): State<Arc<AppContext>>,
-) -> Json<HeartBeatResp> {
-    // get the current time
-    let now = std::time::SystemTime::now().duration_since(UNIX_EPOCH).expect("time went backwards");
-    ctx.last_hb_time.swap(now.as_secs(), Ordering::Acquire);
-    let mut hb_time = vec![];
-
+/// Endpoint (/heartbeat, method=GET): Health of every backend as of the last background check
+pub async fn heartbeat(State(ctx): State<Arc<AppContext>>) -> Json<HeartBeatResp> {
     let servers = ctx.hash_server.read().unwrap().server_containers();
-    // loop through all the configs and see if they are alive
-    for server in servers.iter() {
-        let req_start = Instant::now();
-        // make a request
-        let server_port = format!("http://{}:{}/heartbeat", server.host, server.port);
-
-        let mut dummy_info = HeartBeatInfo::default();
-
-        dummy_info.host = server.host.clone();
-        dummy_info.port = server.port;
-        match ureq::head(&server_port).call() {
-            Ok(c) => {
-                dummy_info.status_code = Some(c.status());
-                dummy_info.status_text = Some(c.status_text().to_string());
-                dummy_info.alive = true;
-            }
-            Err(e) => {
-                dummy_info.error = Some(e.to_string());
-
-                if let Some(resp) = e.into_response() {
-                    dummy_info.status_code = Some(resp.status());
-                    dummy_info.status_text = Some(resp.status_text().to_string());
-                }
-            }
-        }
-        let req_end = Instant::now();
-        dummy_info.time_taken_ms = req_end.duration_since(req_start).as_millis() as u64;
-        hb_time.push(dummy_info);
-    }
-    Json(HeartBeatResp { request_time: now.as_secs(), server_hb: hb_time })
-}
\ No newline at end of file
+    Json(HeartBeatResp {
+        request_time: unix_now(),
+        last_check: ctx.last_hb_time.load(Ordering::Acquire),
+        server_hb: servers.iter().map(|c| c.state.health.report(c)).collect(),
+    })
+}
+
+#[test]
+fn test_health_thresholds() {
+    let conf = HealthConf { healthy_threshold: 2, unhealthy_threshold: 3, ..Default::default() };
+    let state = HealthState::default();
+    let check = |alive: bool| state.record(&conf, HeartBeatInfo { alive, name: "health-test".to_string(), ..Default::default() });
+
+    assert!(state.is_healthy());
+    check(false);
+    check(false);
+    check(true);
+    check(false);
+    check(false);
+    assert!(state.is_healthy());
+    check(false);
+    assert!(!state.is_healthy());
+    assert_eq!(BACKEND_HEALTHY.with_label_values(&["health-test"]).get(), 0.0);
+
+    check(true);
+    assert!(!state.is_healthy());
+    check(true);
+    assert!(state.is_healthy());
+
+    assert!(conf.passes(204) && !conf.passes(503));
+    let conf = HealthConf { expected_status: vec![418], ..conf };
+    assert!(conf.passes(418) && !conf.passes(200));
+}
@@ -1,17 +1,16 @@
-use std::fmt::Pointer;
-use std::process::Command;
-use std::sync::{Arc, LockResult};
+use std::sync::{Arc};
 use std::sync::atomic::Ordering;
 use std::time::Instant;
 use axum::extract::State;
+use axum::http::StatusCode;
 use axum::Json;
-use log::{error, info, trace};
+use log::{error, info, trace, warn};
 use serde::{Deserialize, Serialize};
-use tracing_subscriber::field::display::Messages;
-use tracing_subscriber::fmt::format;
 use crate::AppContext;
-use crate::config::SingleServer;
-use crate::heartbeat::{heartbeat, HeartBeatResp};
+use crate::circuit_breaker::BreakerSnapshot;
+use crate::config::{MAX_WEIGHT, SingleServer};
+use crate::replicas::{fresh_name, is_valid_name};
+use crate::runtime::RuntimeOutput;
 
 
 #[derive(Serialize)]
@@ -33,12 +32,13 @@ pub struct RespResponse {
 /// The response contains the number of replicas and their hostname in the docker internal network:n1 as mentioned in
 /// Fig. 1. An example response is shown below.
 pub async fn rep(State(ctx): State<Arc<AppContext>>) -> Json<RespResponse> {
-    Json(match ctx.app_config.servers.read() {
+    Json(match ctx.hash_server.read() {
         Ok(c) => {
+            let servers = c.server_containers();
             RespResponse {
                 message: RepResponseMessage {
-                    N: c.len(),
-                    replicas: c.iter().map(|c| c.name.to_string()).collect(),
+                    N: servers.len(),
+                    replicas: servers.iter().map(|c| c.name.to_string()).collect(),
                 },
                 status: "successful".to_string(),
             }
@@ -56,119 +56,325 @@ pub async fn rep(State(ctx): State<Arc<AppContext>>) -> Json<RespResponse> {
     })
 }
 
-pub async fn add_server(State(ctx): State<Arc<AppContext>>, Json(payload): Json<RequestLayout>) -> Json<Vec<RmResponse>> {
+/// Endpoint (/add, method=POST): Start `n` replicas and add them to the pool
+///
+/// Replicas are named after `hostnames`, when fewer names than `n` are given the rest get
+/// generated names. Responds with `201 Created` when all replicas started, `207 Multi-Status`
+/// when some did and `502 Bad Gateway` when none did, with the outcome of every replica
+///
+/// ```json
+/// {"n": 2, "hostnames": ["big"]}
+/// ```
+pub async fn add_server(State(ctx): State<Arc<AppContext>>, Json(payload): Json<RequestLayout>) -> Result<(StatusCode, Json<ScaleResponse>), (StatusCode, String)> {
+    let weight = payload.weight.unwrap_or(1);
+    if weight == 0 || weight > MAX_WEIGHT {
+        return Err((StatusCode::BAD_REQUEST, format!("weight must be between 1 and {MAX_WEIGHT}")));
+    }
+    let n = payload.check()?;
+    let free = ctx.ports.unassigned();
+    if n > free {
+        return Err((StatusCode::BAD_REQUEST, format!("n is {} but only {} ports are free in {:?}", n, free, ctx.app_config.replicas.port_range)));
+    }
+    // names are reserved by giving them a port under the write lock, so a concurrent
+    // request cannot pick the same names before these replicas are in the pool
+    let reserved = {
+        let pool = ctx.hash_server.write().unwrap();
+        let assigned = ctx.ports.assigned();
+        if let Some(taken) = payload.hostnames.iter().find(|c| assigned.contains_key(*c) || pool.servers().iter().any(|s| &s.name == *c)) {
+            return Err((StatusCode::BAD_REQUEST, format!("a server named {taken} already exists")));
+        }
+        if !pool.fits(n.saturating_mul(weight)) {
+            return Err((StatusCode::BAD_REQUEST, format!("{n} servers of weight {weight} do not fit on the ring, raise ring.slots or lower ring.virtual_nodes")));
+        }
+        let mut names = payload.hostnames.clone();
+        while names.len() < n {
+            let name = fresh_name(&pool, &ctx.app_config.replicas.name_prefix);
+            if !names.contains(&name) && !assigned.contains_key(&name) {
+                names.push(name);
+            }
+        }
+        names.into_iter().map(|name| {
+            let port = ctx.ports.allocate(&name);
+            (name, port)
+        }).collect::<Vec<_>>()
+    };
     trace!("Starting server add");
     let start = std::time::Instant::now();
     let mut de = vec![];
-    match ctx.hash_server.write() {
-        Ok(mut writer) => {
-            for name in &payload.hostnames {
-                let new_port = ctx.port.fetch_add(1, Ordering::AcqRel);
-
-                let command = Command::new("docker")
-                    .arg("run")
-                    .arg("-d")
-                    .arg("--name")
-                    .arg(name)
-                    .arg("-p")
-                    .arg(format!("{}:8000", new_port))
-                    .arg("-e")
-                    .arg(format!("SERVER_ID={}", name))
-                    .arg("nasa_api").output();
-
-                match command {
-                    Ok(e) => {
-                        if e.status.success() {
-                            writer.add_server(name.to_string(), "127.0.0.1".to_string(), new_port as u16);
-                            info!("Successfully added server: Output: {:?}",e);
-                            de.push(RmResponse {
-                                name: name.to_owned(),
-                                status: e.status.code().unwrap_or(-255),
-                                stdout: String::from_utf8_lossy(&e.stdout).trim().to_string(),
-                                stderr: String::from_utf8_lossy(&e.stderr).trim().to_string(),
-                            });
-                        } else {
-                            error!("Could not add a server  status code failed");
-                            de.push(RmResponse {
-                                name: name.to_owned(),
-                                status: e.status.code().unwrap_or(-255),
-                                stdout: String::from_utf8_lossy(&e.stdout).trim().to_string(),
-                                stderr: String::from_utf8_lossy(&e.stderr).trim().to_string(),
-                            });
-                        }
-                    }
-                    Err(e) => {
-                        error!("An error occurred :{}",e);
-                    }
-                }
+    for (name, port) in &reserved {
+        let Some(new_port) = *port else {
+            error!("Could not add server {}, no ports left in {:?}", name, ctx.app_config.replicas.port_range);
+            de.push(ReplicaOutcome::failed(name, format!("no ports left in {:?}", ctx.app_config.replicas.port_range)));
+            continue;
+        };
+
+        match ctx.runtime.start(name, new_port).await {
+            Ok(e) if e.success => {
+                ctx.hash_server.write().unwrap().add_server(name.to_string(), ctx.runtime.host().to_string(), new_port, weight);
+                ctx.desired_replicas.fetch_add(1, Ordering::AcqRel);
+                info!("Successfully added server: Output: {:?}",e);
+                de.push(ReplicaOutcome::new(name, "added", Some(new_port), e));
+            }
+            Ok(e) => {
+                error!("Could not add server {}: {}", name, e.stderr);
+                ctx.ports.release(name);
+                de.push(ReplicaOutcome::new(name, "failed", Some(new_port), e));
+            }
+            Err(e) => {
+                error!("An error occurred :{}",e);
+                ctx.ports.release(name);
+                de.push(ReplicaOutcome::failed(name, e));
             }
-        }
-        Err(e) => {
-            error!("Could not add server, poisoned mutex, reason:{:?}",e);
         }
     }
     let stop = Instant::now();
     trace!("Took {:?} ms to add server", stop.duration_since(start).as_millis());
-    return Json(de);
+    Ok(ScaleResponse::respond(StatusCode::CREATED, de))
 }
 
-fn create_docker_instance() {}
-
-/// `rm` command endpoint
+/// `add` and `rm` command endpoint
 #[derive(Deserialize)]
 pub struct RequestLayout {
-    n: usize,
+    /// Number of replicas, defaults to the number of hostnames
+    n: Option<usize>,
+    #[serde(default)]
     hostnames: Vec<String>,
+    /// Weight of added servers, defaults to 1, at most [`MAX_WEIGHT`]
+    weight: Option<usize>,
+}
+
+impl RequestLayout {
+    /// `n`, which has to cover all hostnames, which have to be unique and valid replica names
+    fn check(&self) -> Result<usize, (StatusCode, String)> {
+        let n = self.n.unwrap_or(self.hostnames.len());
+        if n == 0 {
+            return Err((StatusCode::BAD_REQUEST, "n must be at least 1".to_string()));
+        }
+        if n < self.hostnames.len() {
+            return Err((StatusCode::BAD_REQUEST, format!("n is {} but {} hostnames were given", n, self.hostnames.len())));
+        }
+        if let Some((i, name)) = self.hostnames.iter().enumerate().find(|(i, c)| self.hostnames[..*i].contains(c)) {
+            return Err((StatusCode::BAD_REQUEST, format!("hostname {name} is given more than once (at {i})")));
+        }
+        if let Some(name) = self.hostnames.iter().find(|c| !is_valid_name(c)) {
+            return Err((StatusCode::BAD_REQUEST, format!("hostname {name:?} must be letters, digits, - and _ only")));
+        }
+        Ok(n)
+    }
 }
 
+/// Outcome of starting or removing one replica
 #[derive(Serialize)]
-pub struct RmResponse {
+pub struct ReplicaOutcome {
     name: String,
+    /// `added`, `removed` or `failed`
+    outcome: &'static str,
+    port: Option<u16>,
+    /// Exit code of the runtime, or the HTTP status for the Docker Engine API
     status: i32,
     stdout: String,
     stderr: String,
 }
 
-///  Endpoint (/rm, method=DELETE): This endpoint removes server instances in the load balancer to scale down with
-/// decreasing client or system maintenance. The endpoint expects a JSON payload that mentions the number of instances
-/// to be removed and their preferred hostnames (same as container name in docker) in a list. An example request and response
-/// is below.
-pub async fn remove_server(State(ctx): State<Arc<AppContext>>, Json(payload): Json<RequestLayout>) -> Json<Vec<RmResponse>> {
+impl ReplicaOutcome {
+    fn new(name: &str, outcome: &'static str, port: Option<u16>, output: RuntimeOutput) -> ReplicaOutcome {
+        ReplicaOutcome { name: name.to_owned(), outcome, port, status: output.status, stdout: output.stdout, stderr: output.stderr }
+    }
+
+    /// The runtime could not be run at all
+    fn failed(name: &str, error: String) -> ReplicaOutcome {
+        ReplicaOutcome { name: name.to_owned(), outcome: "failed", port: None, status: -1, stdout: String::new(), stderr: error }
+    }
+}
 
-    //if
-    //docker rm -f mycontainer
+#[derive(Serialize)]
+pub struct ScaleResponse {
+    message: Vec<ReplicaOutcome>,
+    /// `successful`, `partial` when some replicas failed or `error` when all did
+    status: String,
+}
+
+impl ScaleResponse {
+    /// `success` when every replica succeeded, `207 Multi-Status` when some did and
+    /// `502 Bad Gateway` when none did, as the runtime failed
+    fn respond(success: StatusCode, outcomes: Vec<ReplicaOutcome>) -> (StatusCode, Json<ScaleResponse>) {
+        let failed = outcomes.iter().filter(|c| c.outcome == "failed").count();
+        let (code, status) = match failed {
+            0 => (success, "successful"),
+            c if c < outcomes.len() => (StatusCode::MULTI_STATUS, "partial"),
+            _ => (StatusCode::BAD_GATEWAY, "error"),
+        };
+        (code, Json(ScaleResponse { message: outcomes, status: status.to_string() }))
+    }
+}
+
+/// Replicas to remove besides the `named` ones, `count` of them
+///
+/// Replicas that fail their health checks or are ejected go first, then the most recently added
+fn pick_victims(replicas: &[&SingleServer], named: &[String], count: usize) -> Vec<String> {
+    let mut candidates: Vec<&SingleServer> = replicas.iter().rev().copied().filter(|c| !named.contains(&c.name)).collect();
+    candidates.sort_by_key(|c| c.state.health.is_healthy() && !c.state.outlier.is_ejected(&c.name));
+    candidates.into_iter().take(count).map(|c| c.name.clone()).collect()
+}
+
+///  Endpoint (/rm, method=POST): This endpoint removes server instances in the load balancer to scale down with
+/// decreasing client or system maintenance. The endpoint expects a JSON payload that mentions the number of instances
+/// to be removed and their preferred hostnames (same as container name in docker) in a list. When fewer hostnames than
+/// `n` are given klein picks the rest, servers failing their health checks first. Only replicas klein started can be
+/// removed, servers declared in the config are removed by taking them out of the config. An example request is below.
+///
+/// ```json
+/// {"n": 2, "hostnames": ["big"]}
+/// ```
+///
+/// Responds with `200 OK` when all replicas were removed, `207 Multi-Status` when some were and `502 Bad Gateway`
+/// when none were, with the outcome of every replica. Replicas that could not be stopped stay in the pool
+pub async fn remove_server(State(ctx): State<Arc<AppContext>>, Json(payload): Json<RequestLayout>) -> Result<(StatusCode, Json<ScaleResponse>), (StatusCode, String)> {
+    let n = payload.check()?;
+    let names = {
+        let pool = ctx.hash_server.read().unwrap();
+        if let Some(missing) = payload.hostnames.iter().find(|c| pool.servers().iter().all(|s| &s.name != *c)) {
+            return Err((StatusCode::NOT_FOUND, format!("No server named {missing}")));
+        }
+        let assigned = ctx.ports.assigned();
+        if let Some(declared) = payload.hostnames.iter().find(|c| !assigned.contains_key(*c)) {
+            return Err((StatusCode::BAD_REQUEST, format!("{declared} was not started by klein, remove it from the config instead")));
+        }
+        let replicas: Vec<&SingleServer> = pool.servers().iter().filter(|c| assigned.contains_key(&c.name)).collect();
+        if n > replicas.len() {
+            return Err((StatusCode::BAD_REQUEST, format!("n is {} but only {} replicas are running", n, replicas.len())));
+        }
+        let mut names = payload.hostnames.clone();
+        names.extend(pick_victims(&replicas, &payload.hostnames, n - payload.hostnames.len()));
+        names
+    };
     let mut de = vec![];
 
-    match ctx.app_config.servers.write() {
-        Ok(mut writer) => {
-            let new_port = ctx.port.fetch_add(1, Ordering::AcqRel);
-
-            for name in &payload.hostnames {
-                let command = Command::new("docker")
-                    .arg("rm")
-                    .arg("-f")
-                    .arg(name)
-                    .output();
-                match command {
-                    Ok(e) => {
-                        info!("Successfully removed server: Output: {:?}",e);
-                        de.push(RmResponse {
-                            name: name.to_owned(),
-                            status: e.status.code().unwrap_or(-255),
-                            stdout: String::from_utf8_lossy(&e.stdout).trim().to_string(),
-                            stderr: String::from_utf8_lossy(&e.stderr).trim().to_string(),
-                        });
-                    }
-                    Err(e) => {
-                        error!("An error occurred :{}",e);
-                    }
-                }
-                writer.iter().position(|c| &c.name == name);
+    for name in &names {
+        // stop routing to the server before it goes away
+        let Some(server) = ctx.hash_server.write().unwrap().remove_server(name) else {
+            warn!("Server {} was removed by someone else", name);
+            continue;
+        };
+        info!("Removed server {} ({}:{}) from the ring", server.name, server.host, server.port);
+
+        let outcome = match ctx.runtime.stop(name).await {
+            Ok(e) => {
+                info!("Removed server {}: Output: {:?}", name, e);
+                let outcome = if e.success { "removed" } else { "failed" };
+                ReplicaOutcome::new(name, outcome, Some(server.port), e)
+            }
+            Err(e) => {
+                error!("An error occurred :{}",e);
+                ReplicaOutcome::failed(name, e)
             }
+        };
+        if outcome.outcome == "removed" {
+            // the supervisor should not bring it back
+            let _ = ctx.desired_replicas.fetch_update(Ordering::AcqRel, Ordering::Acquire, |c| Some(c.saturating_sub(1)));
+            ctx.ports.release(name);
+        } else {
+            warn!("Could not stop {}, putting it back in the ring", name);
+            ctx.hash_server.write().unwrap().insert_server(server);
         }
+        de.push(outcome);
+    }
+    Ok(ScaleResponse::respond(StatusCode::OK, de))
+}
+
+#[derive(Deserialize)]
+pub struct WeightRequest {
+    name: String,
+    weight: usize,
+}
+
+#[derive(Serialize)]
+pub struct WeightResponse {
+    message: String,
+    status: String,
+}
+
+/// Endpoint (/weight, method=POST): Change the weight of a server at runtime
+///
+/// Only the virtual servers of that server are moved on the ring. Example request
+///
+/// ```json
+/// {"name": "big", "weight": 3}
+/// ```
+pub async fn update_weight(State(ctx): State<Arc<AppContext>>, Json(payload): Json<WeightRequest>) -> (StatusCode, Json<WeightResponse>) {
+    let respond = |code: StatusCode, message: String| {
+        let status = if code.is_success() { "successful" } else { "error" };
+        (code, Json(WeightResponse { message, status: status.to_string() }))
+    };
+    if payload.weight == 0 || payload.weight > MAX_WEIGHT {
+        return respond(StatusCode::BAD_REQUEST, format!("weight must be between 1 and {MAX_WEIGHT}"));
+    }
+    match ctx.hash_server.write() {
+        Ok(writer) if writer.servers().iter().any(|c| c.name == payload.name && !writer.fits(payload.weight.saturating_sub(c.weight))) =>
+            respond(StatusCode::BAD_REQUEST, format!("weight {} does not fit on the ring, raise ring.slots or lower ring.virtual_nodes", payload.weight)),
+        Ok(mut writer) => match writer.set_weight(&payload.name, payload.weight) {
+            Some(previous) => {
+                info!("Changed weight of {} from {} to {}", payload.name, previous, payload.weight);
+                respond(StatusCode::OK, format!("Changed weight of {} from {} to {}", payload.name, previous, payload.weight))
+            }
+            None => respond(StatusCode::NOT_FOUND, format!("No server named {}", payload.name)),
+        },
         Err(e) => {
-            error!("Could not add server, poisoned mutex, reason:{:?}",e);
+            error!("Could not change weight, poisoned mutex, reason:{:?}",e);
+            respond(StatusCode::INTERNAL_SERVER_ERROR, "poisoned mutex".to_string())
         }
     }
-    Json(de)
-}
\ No newline at end of file
+}
+
+#[derive(Serialize)]
+pub struct BreakerEntry {
+    name: String,
+    #[serde(flatten)]
+    breaker: BreakerSnapshot,
+}
+
+#[derive(Serialize)]
+pub struct BreakersResponse {
+    message: Vec<BreakerEntry>,
+    status: String,
+}
+
+/// Endpoint (/breakers, method=GET): Circuit breaker state of every server
+///
+/// Servers with an `open` breaker are not sent requests until their cooldown is over,
+/// `half_open` ones get a few probe requests. Example response
+///
+/// ```json
+/// {"message": [{"name": "n1", "state": "open", "consecutive_failures": 0, "requests": 0, "failures": 0}], "status": "successful"}
+/// ```
+pub async fn breakers(State(ctx): State<Arc<AppContext>>) -> Json<BreakersResponse> {
+    Json(match ctx.hash_server.read() {
+        Ok(c) => BreakersResponse {
+            message: c.servers().iter()
+                .map(|c| BreakerEntry { name: c.name.clone(), breaker: c.state.breaker.snapshot(&c.name) })
+                .collect(),
+            status: "successful".to_string(),
+        },
+        Err(e) => {
+            error!("An error occurred, poisoned mutex: {:?}",e);
+            BreakersResponse { message: vec![], status: "error".to_string() }
+        }
+    })
+}
+
+#[test]
+fn test_pick_victims() {
+    let pool = crate::consistent_hashing::test_pool(Default::default(), 5);
+    let servers: Vec<&SingleServer> = pool.servers().iter().collect();
+
+    // the newest servers go first
+    assert_eq!(pick_victims(&servers, &[], 2), ["server-4", "server-3"]);
+    assert_eq!(pick_victims(&servers, &["server-4".to_string()], 2), ["server-3", "server-2"]);
+
+    // then unhealthy ones jump the queue
+    for _ in 0..3 {
+        servers[1].state.health.record_check(&Default::default(), false, "server-1");
+    }
+    assert_eq!(pick_victims(&servers, &[], 2), ["server-1", "server-4"]);
+    assert_eq!(pick_victims(&servers, &[], 9).len(), 5);
+}
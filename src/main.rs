@@ -4,16 +4,30 @@
 //! cargo run -p example-hello-world
 //! ```
 
+mod affinity;
+mod balancing;
+mod circuit_breaker;
 mod config;
+mod forwarding;
 mod load_balancer;
+mod outlier;
 mod consistent_hashing;
+mod hashers;
 mod heartbeat;
+mod placement;
+mod ports;
 mod prometheus_stats;
-
-use std::io::Read;
-use std::sync::{Arc, Mutex, RwLock};
-use std::sync::atomic::{AtomicU64, Ordering};
-use std::time::{Instant};
+mod proxy;
+mod reload;
+mod replicas;
+mod retry;
+mod runtime;
+mod server_state;
+mod timeouts;
+
+use std::net::SocketAddr;
+use std::sync::{Arc, RwLock};
+use std::sync::atomic::{AtomicU64, AtomicUsize};
 use axum::{routing::get, Router, Json};
 use axum::body::Body;
 use axum::extract::{Request, State};
@@ -21,15 +35,20 @@ use axum::http::{StatusCode};
 use axum::response::Response;
 use axum::routing::{any, post};
 use log::{error, info, trace, warn};
-use nanorand::Rng;
 use prometheus::{Encoder, TextEncoder};
 use serde::{Serialize};
 use tracing_subscriber::prelude::*;
-use crate::config::{AppConfig, read_config, SingleServer};
+use crate::affinity::request_key;
+use crate::balancing::Balancer;
+use crate::config::{AppConfig, ConfigLoader, read_config, SingleServer};
 use crate::consistent_hashing::{ServerPool};
 use crate::heartbeat::{heartbeat};
-use crate::load_balancer::{add_server, remove_server, rep};
-use crate::prometheus_stats::{HTTP_COUNTER, HTTP_NUM_REQUESTS, HTTP_REQ_HISTOGRAM, HTTP_RESPONSE_STATUS};
+use crate::load_balancer::{add_server, breakers, remove_server, rep, update_weight};
+use crate::ports::PortAllocator;
+use crate::prometheus_stats::{HTTP_COUNTER, HTTP_NUM_REQUESTS, HTTP_REQ_HISTOGRAM, RETRIES};
+use crate::proxy::UpstreamFailure;
+use crate::retry::{Replay, RetryBudget};
+use crate::runtime::{new_runtime, ReplicaRuntime};
 
 /// Initialize the logging library
 ///
@@ -49,68 +68,67 @@ fn init_log() {
 #[derive(Clone)]
 struct AppContext {
     hash_server: Arc<RwLock<ServerPool>>,
+    // Strategies picking a server from the pool, replaced on reload
+    balancer: Arc<RwLock<Balancer>>,
+    // Limits retries across all requests
+    retry_budget: Arc<RetryBudget>,
     // App configuration
     app_config: Arc<AppConfig>,
-    // Last time we had a heartbeat from the server
+    // Last time a round of health checks finished
     last_hb_time: Arc<AtomicU64>,
-    port: Arc<AtomicU64>,
-    request_rand_gen: Arc<Mutex<nanorand::WyRand>>,
+    // Ports given to replicas
+    ports: Arc<PortAllocator>,
+    // Replicas the supervisor keeps running
+    desired_replicas: Arc<AtomicUsize>,
+    // Starts and stops replicas
+    runtime: Arc<dyn ReplicaRuntime>,
 }
 
 impl AppContext {
     fn new(app_config: AppConfig) -> AppContext {
-        return AppContext {
-            hash_server: Arc::new(RwLock::new(ServerPool::new(0))),
-            app_config: Arc::new(app_config),
-            last_hb_time: Arc::new(AtomicU64::new(0)),
-            port: Arc::new(AtomicU64::new(18000)),
-            request_rand_gen: Arc::new(Mutex::new(nanorand::WyRand::new_seed(37))),
-        };
-    }
-}
+        let mut pool = ServerPool::new(app_config.ring.clone());
 
-fn handle_request(mut req: ureq::Request, server_name: &str, incoming: axum::extract::Request) -> Response {
-    // add headers from request
-    for (k, v) in incoming.headers() {
-        req = req.set(&k.to_string(), v.to_str().unwrap());
-    }
-
-    let start = Instant::now();
-
-    // call it finally
-    return match req.call() {
-        Ok(e) => {
-            let mut data = Vec::new();
-            let status = e.status();
-
-            e.into_reader().read_to_end(&mut data).unwrap();
-            HTTP_RESPONSE_STATUS.with_label_values(&[status.to_string().as_str(), server_name]).inc();
-
-            let end = Instant::now();
-            trace!("Took {:?} ms to get response\n",end.duration_since(start).as_millis());
-            // return response
-            Response::builder().status(status).body(Body::from(data)).unwrap()
+        // add servers declared in the config file
+        for server in app_config.servers.read().unwrap().iter() {
+            info!("Adding server {} at {}:{} (weight={})", server.name, server.host, server.port, server.weight);
+            pool.insert_server(server.clone());
         }
-        Err(f) => {
-            warn!("Error occurred when making request:  {:?}",f);
-            if let Some(resp) = f.into_response() {
-                HTTP_RESPONSE_STATUS.with_label_values(&[resp.status().to_string().as_str(), server_name]).inc();
 
-                return Response::builder().status(resp.status()).body(Body::from(resp.into_string().unwrap())).unwrap();
+        let replicas = &app_config.replicas;
+        let ports = PortAllocator::new(replicas.port_range, replicas.port_state_file.clone());
+        let runtime = new_runtime(&app_config.replicas);
+
+        // replicas that kept running while klein was down
+        for (name, port) in ports.assigned() {
+            if pool.servers().iter().any(|c| c.name == name) {
+                warn!("Replica {} has the name of a server in the config, releasing its port {}", name, port);
+                ports.release(&name);
+            } else if runtime.adopt(&name) {
+                info!("Adding replica {} at {}:{} that was running before the restart", name, runtime.host(), port);
+                pool.add_server(name, runtime.host().to_string(), port, 1);
+            } else {
+                warn!("Replica {} on port {} was started before the restart and cannot be stopped, releasing its port", name, port);
+                ports.release(&name);
             }
-            Response::builder().status(StatusCode::INTERNAL_SERVER_ERROR).body(Body::from("An Error occurred, please fix it")).unwrap()
         }
-    };
+        let desired_replicas = app_config.replicas.count.unwrap_or(ports.assigned().len());
+        AppContext {
+            hash_server: Arc::new(RwLock::new(pool)),
+            balancer: Arc::new(RwLock::new(Balancer::new(&app_config.balancing, &app_config.routes))),
+            retry_budget: Arc::new(RetryBudget::new()),
+            app_config: Arc::new(app_config),
+            last_hb_time: Arc::new(AtomicU64::new(0)),
+            ports: Arc::new(ports),
+            desired_replicas: Arc::new(AtomicUsize::new(desired_replicas)),
+            runtime: Arc::from(runtime),
+        }
+    }
 }
 
+fn get_server(values: &AppContext, to: &str, path: &str, key: Option<&[u8]>, exclude: &[String]) -> Option<SingleServer> {
+    let balancer = values.balancer.read().unwrap();
 
-fn get_server(values: &AppContext, to: String) -> Option<SingleServer> {
-    // get the request generator indicator
-    let request_rand_gen = values.request_rand_gen.lock().unwrap().generate_range(100_000..999_999);
-
-    info!("Assigning request {} id {}",to,request_rand_gen);
-
-    match values.hash_server.write().unwrap().get_server_container(request_rand_gen) {
+    match balancer.strategy(path).pick(&values.hash_server.read().unwrap(), key, exclude) {
         None => {
             error!("Could not get the server");
             None
@@ -122,39 +140,78 @@ fn get_server(values: &AppContext, to: String) -> Option<SingleServer> {
     }
 }
 
+// Send a request to one server
+async fn proxy_to(ctx: &AppContext, server: &SingleServer, req: Request) -> Response {
+    let timer = HTTP_REQ_HISTOGRAM.with_label_values(&[server.name.as_str()]).start_timer();
+
+    HTTP_NUM_REQUESTS.inc();
+    let _in_flight = server.state.start_request(&server.name);
+    let probe = server.state.breaker.start(&server.name);
+    let config = &ctx.app_config;
+    let route_timeouts = ctx.balancer.read().unwrap().route(req.uri().path()).map(|c| c.timeouts).unwrap_or_default();
+    let timeouts = route_timeouts.or(&config.timeouts(server));
+    let client = config.client(server);
+
+    let c = proxy::handle_request(&client, server, &config.limits, &config.forwarding, &timeouts, req).await;
+    let upstream_failure = c.extensions().get::<UpstreamFailure>().is_some();
+    let success = !upstream_failure && !c.status().is_server_error();
+    server.state.breaker.record(&server.name, &config.circuit_breaker, success, probe);
+    if config.outlier_detection.enabled {
+        if let Some(reason) = server.state.outlier.observe(&config.outlier_detection, c.status(), upstream_failure) {
+            outlier::try_eject(&ctx.hash_server.read().unwrap(), server, &config.outlier_detection, reason);
+        }
+    }
+
+    timer.observe_duration();
+
+    HTTP_NUM_REQUESTS.dec();
+    c
+}
 
 async fn re_router(State(ctx): State<Arc<AppContext>>, req: Request) -> Response {
     HTTP_COUNTER.inc();
+    ctx.retry_budget.deposit();
 
     // choose server
-    match get_server(&ctx, req.uri().to_string()) {
-        Some(server) => {
-            let timer = HTTP_REQ_HISTOGRAM.with_label_values(&[server.name.as_str()]).start_timer();
+    let key = request_key(&ctx.app_config.affinity, &ctx.app_config.forwarding, &req);
+    let (to, path) = (req.uri().to_string(), req.uri().path().to_string());
+    let retries = &ctx.app_config.retries;
 
-            HTTP_NUM_REQUESTS.inc();
-            let uri = req.uri();
-            // create base url
-            let base_url = format!("http://{}:{}{}", server.host, server.port, uri.path_and_query().map(|c| c.to_string()).unwrap_or(String::new()));
-            trace!("URL {}",base_url);
-            let method = req.method().to_owned();
-            let req_method = ureq::request(method.to_string().as_str(), &base_url);
+    let mut replay = match Replay::new(req, retries).await {
+        Ok(replay) => replay,
+        Err(response) => return response,
+    };
+    let Some(mut server) = get_server(&ctx, &to, &path, key.as_deref(), &[]) else {
+        let response = Response::new(Body::from("no backend server is up"));
+        let (mut parts, body) = response.into_parts();
 
-            let c = handle_request(req_method, &server.name, req);
+        parts.status = StatusCode::INTERNAL_SERVER_ERROR;
+        return Response::from_parts(parts, body);
+    };
+    let mut failed = vec![];
 
-            timer.observe_duration();
+    loop {
+        let response = proxy_to(&ctx, &server, replay.request()).await;
 
-            HTTP_NUM_REQUESTS.dec();
-            return c;
+        if !replay.can_retry() || failed.len() + 1 >= retries.max_attempts || !retries.should_retry(&response) {
+            return response;
         }
-        None => {
-            let response = Response::new(Body::from("no backend server is up"));
-            let (mut parts, body) = response.into_parts();
-
-            parts.status = StatusCode::INTERNAL_SERVER_ERROR;
-            let response = Response::from_parts(parts, body);
+        // walk past every server that failed this request
+        failed.push(server.name.clone());
+        let Some(next) = get_server(&ctx, &to, &path, key.as_deref(), &failed) else {
+            return response;
+        };
+        if !ctx.retry_budget.withdraw(retries) {
+            warn!("Retry budget exhausted, not retrying request {} that failed on {}", to, server.name);
             return response;
         }
-    };
+        info!("Request {} failed on {} with {}, retrying on {}", to, server.name, response.status(), next.name);
+        RETRIES.with_label_values(&[server.name.as_str()]).inc();
+        drop(response);
+
+        retries.backoff(failed.len() as u32).await;
+        server = next;
+    }
 }
 
 async fn stats() -> Response<Body> {
@@ -173,32 +230,53 @@ async fn stats() -> Response<Body> {
     response
 }
 
+fn app(ctx: Arc<AppContext>) -> Router {
+    Router::new()
+        .fallback(any(re_router))
+        .route("/heartbeat", get(heartbeat))
+        .route("/home", get(home_endpoint))
+        .route("/add", post(add_server))
+        .route("/rm", post(remove_server))
+        .route("/metrics", get(stats))
+        .route("/rep", get(rep))
+        .route("/weight", post(update_weight))
+        .route("/breakers", get(breakers))
+        .with_state(ctx)
+}
+
 #[tokio::main]
 async fn main() {
     // initialize logging
     init_log();
     // read toml file containing configs
-    match read_config() {
+    match ConfigLoader::from_args().and_then(read_config) {
         Ok(config) => {
             let (h, p) = (config.host.to_owned(), config.port);
-            let ctx = AppContext::new(config);
+            let ctx = Arc::new(AppContext::new(config));
+
+            // pick up config changes without restarting
+            tokio::spawn(reload::watch_config(ctx.clone()));
+            if ctx.app_config.health_check.enabled {
+                tokio::spawn(heartbeat::health_checks(ctx.clone()));
+            }
+            if ctx.app_config.outlier_detection.enabled {
+                tokio::spawn(outlier::outlier_detection(ctx.clone()));
+            }
+            if ctx.app_config.replicas.supervise {
+                if !ctx.app_config.health_check.enabled {
+                    warn!("Health checks are disabled, dead replicas will not be replaced");
+                }
+                tokio::spawn(replicas::supervise(ctx.clone()));
+            }
 
             // build our application with a route
-            let app = Router::new()
-                .fallback(any(re_router))
-                .route("/heartbeat", get(heartbeat))
-                .route("/home", get(home_endpoint))
-                .route("/add", post(add_server))
-                .route("/rm", post(remove_server))
-                .route("/metrics", get(stats))
-                .route("/rep", get(rep))
-                .with_state(Arc::new(ctx));
+            let app = app(ctx);
             // run it
             match tokio::net::TcpListener::bind(format!("{}:{}", h, p))
                 .await {
                 Ok(listener) => {
                     info!("listening on {}\n", listener.local_addr().unwrap());
-                    axum::serve(listener, app).await.unwrap();
+                    axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>()).await.unwrap();
                 }
                 Err(e) => {
                     error!("Could not bind to address: {e}");
@@ -219,7 +297,7 @@ struct HomeResp {
 }
 
 async fn home_endpoint(State(ctx): State<Arc<AppContext>>) -> Json<HomeResp> {
-    Json(match get_server(&ctx, "/home".to_string()) {
+    Json(match get_server(&ctx, "/home", "/home", None, &[]) {
         None => {
             HomeResp {
                 message: "Could not get server".to_string(),
@@ -236,3 +314,440 @@ async fn home_endpoint(State(ctx): State<Arc<AppContext>>) -> Json<HomeResp> {
     })
 }
 
+
+// Spawn a backend that answers every request after `delay`, tracking the most concurrent requests it saw
+#[cfg(test)]
+async fn spawn_slow_backend(delay: std::time::Duration) -> (SocketAddr, Arc<std::sync::atomic::AtomicUsize>) {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    let current = Arc::new(AtomicUsize::new(0));
+    let peak = Arc::new(AtomicUsize::new(0));
+    let (c, p) = (current.clone(), peak.clone());
+    let backend = Router::new().fallback(any(move || {
+        let (current, peak) = (c.clone(), p.clone());
+        async move {
+            peak.fetch_max(current.fetch_add(1, Ordering::AcqRel) + 1, Ordering::AcqRel);
+            tokio::time::sleep(delay).await;
+            current.fetch_sub(1, Ordering::AcqRel);
+            "slow hello"
+        }
+    }));
+    (proxy::spawn_backend(backend).await, peak)
+}
+
+// Spawn klein with the given config, servers can be added to the returned context
+#[cfg(test)]
+async fn spawn_klein(contents: &str) -> (SocketAddr, Arc<AppContext>) {
+    let ctx = Arc::new(AppContext::new(config::test_app_config(contents)));
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let service = app(ctx.clone()).into_make_service_with_connect_info::<SocketAddr>();
+    tokio::spawn(async move { axum::serve(listener, service).await.unwrap() });
+    (addr, ctx)
+}
+
+// Many more requests than worker threads wait on a slow backend at the same time
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_concurrent_slow_backend() {
+    const REQUESTS: usize = 200;
+    let delay = std::time::Duration::from_millis(300);
+
+    let (backend, peak) = spawn_slow_backend(delay).await;
+    let (klein, _ctx) = spawn_klein(&format!("port = 1\nhost = \"127.0.0.1\"\n\
+        [servers.slow]\nhost = \"127.0.0.1\"\nport = {}\nname = \"slow\"", backend.port())).await;
+
+    let client = proxy::build_client(&Default::default(), None);
+    let start = std::time::Instant::now();
+    let requests: Vec<_> = (0..REQUESTS).map(|i| {
+        let client = client.clone();
+        tokio::spawn(async move {
+            let req = Request::get(format!("http://{klein}/slow/{i}")).body(Body::empty()).unwrap();
+            client.request(req).await.unwrap().status()
+        })
+    }).collect();
+    for request in requests {
+        assert_eq!(request.await.unwrap(), StatusCode::OK);
+    }
+    let elapsed = start.elapsed();
+
+    // blocking on the backend would serve 2 requests at a time and take REQUESTS / 2 * delay
+    let peak = peak.load(std::sync::atomic::Ordering::Acquire);
+    assert!(peak > REQUESTS / 2, "at most {peak} requests reached the backend at once");
+    assert!(elapsed < delay * 5, "{REQUESTS} requests took {elapsed:?}");
+}
+
+// Headers are forwarded in both directions, except hop-by-hop ones
+#[tokio::test]
+async fn test_forwarded_headers() {
+    use axum::http::{HeaderMap, HeaderValue};
+    use http_body_util::BodyExt;
+
+    // the backend answers with the headers it received, one `name: value` per line
+    let backend = proxy::spawn_backend(Router::new().fallback(any(|headers: HeaderMap| async move {
+        let received: String = headers.iter()
+            .map(|(k, v)| format!("{}: {}\n", k, String::from_utf8_lossy(v.as_bytes())))
+            .collect();
+        Response::builder()
+            .header("content-type", "application/json")
+            .header("cache-control", "max-age=60")
+            .header("set-cookie", "session=abc")
+            .header("set-cookie", "theme=dark")
+            .header("connection", "x-backend-internal")
+            .header("x-backend-internal", "secret")
+            .header("keep-alive", "timeout=5")
+            .body(Body::from(received))
+            .unwrap()
+    }))).await;
+    let (klein, _ctx) = spawn_klein(&format!("port = 1\nhost = \"127.0.0.1\"\n\
+        [servers.echo]\nhost = \"127.0.0.1\"\nport = {}\nname = \"echo\"", backend.port())).await;
+
+    let req = Request::get(format!("http://{klein}/neo/feed"))
+        .header("connection", "x-client-internal")
+        .header("x-client-internal", "secret")
+        .header("proxy-authorization", "Basic a2xlaW4=")
+        .header("te", "trailers")
+        .header("x-request-id", "42")
+        .header("x-latin1", HeaderValue::from_bytes(b"caf\xe9").unwrap())
+        .body(Body::empty())
+        .unwrap();
+    let response = proxy::build_client(&Default::default(), None).request(req).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let headers = response.headers().clone();
+    assert_eq!(headers["content-type"], "application/json");
+    assert_eq!(headers["cache-control"], "max-age=60");
+    assert_eq!(headers.get_all("set-cookie").iter().collect::<Vec<_>>(), ["session=abc", "theme=dark"]);
+    assert!(headers.get("x-backend-internal").is_none());
+    assert!(headers.get("keep-alive").is_none());
+
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    let received = String::from_utf8_lossy(&body);
+    assert!(received.contains("x-request-id: 42\n"), "{received}");
+    assert!(received.contains("x-latin1: caf\u{fffd}\n"), "{received}");
+    assert!(received.contains(&format!("host: {backend}\n")), "{received}");
+    assert!(received.contains("x-forwarded-for: 127.0.0.1\n"), "{received}");
+    assert!(received.contains(&format!("x-forwarded-host: {klein}\n")), "{received}");
+    assert!(received.contains("via: 1.1 klein\n"), "{received}");
+    for name in ["connection", "x-client-internal", "proxy-authorization", "te"] {
+        assert!(!received.contains(&format!("{name}:")), "{name} was forwarded: {received}");
+    }
+}
+
+// Idempotent requests that fail are retried on other servers, others are not
+#[tokio::test]
+async fn test_retries() {
+    let dead = {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.local_addr().unwrap()
+    };
+    let unavailable = proxy::spawn_backend(Router::new().fallback(any(|| async { StatusCode::SERVICE_UNAVAILABLE }))).await;
+    let good = proxy::spawn_backend(Router::new().fallback(any(|| async { "hello" }))).await;
+    let server = |name: &str, addr: SocketAddr| format!("[servers.{name}]\nhost = \"127.0.0.1\"\nport = {}\nname = \"retry-{name}\"\n", addr.port());
+    let (klein, _ctx) = spawn_klein(&format!("port = 1\nhost = \"127.0.0.1\"\n\
+        [balancing]\nstrategy = \"round_robin\"\n[retries]\nmax_attempts = 3\nbackoff_base_ms = 1\n\
+        [circuit_breaker]\nenabled = false\n[outlier_detection]\nenabled = false\n{}{}{}",
+        server("dead", dead), server("unavailable", unavailable), server("good", good))).await;
+
+    let client = proxy::build_client(&Default::default(), None);
+    let send = |method: &'static str| {
+        let req = Request::builder().method(method).uri(format!("http://{klein}/neo")).body(Body::empty()).unwrap();
+        let client = client.clone();
+        async move { client.request(req).await.unwrap().status() }
+    };
+    for _ in 0..12 {
+        assert_eq!(send("GET").await, StatusCode::OK);
+    }
+    let retried = |name: &str| RETRIES.with_label_values(&[name]).get();
+    assert!(retried("retry-dead") > 0.0 && retried("retry-unavailable") > 0.0);
+    assert_eq!(retried("retry-good"), 0.0);
+
+    let mut statuses = vec![];
+    for _ in 0..12 {
+        statuses.push(send("POST").await);
+    }
+    assert!(statuses.contains(&StatusCode::BAD_GATEWAY), "{statuses:?}");
+    assert!(statuses.contains(&StatusCode::SERVICE_UNAVAILABLE), "{statuses:?}");
+}
+
+// A failing server is taken out of selection once its breaker trips
+#[tokio::test]
+async fn test_circuit_breakers() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use http_body_util::BodyExt;
+
+    let hits = Arc::new(AtomicUsize::new(0));
+    let h = hits.clone();
+    let failing = proxy::spawn_backend(Router::new().fallback(any(move || {
+        h.fetch_add(1, Ordering::AcqRel);
+        async { StatusCode::INTERNAL_SERVER_ERROR }
+    }))).await;
+    let good = proxy::spawn_backend(Router::new().fallback(any(|| async { "hello" }))).await;
+    let server = |name: &str, addr: SocketAddr| format!("[servers.{name}]\nhost = \"127.0.0.1\"\nport = {}\nname = \"breaker-{name}\"\n", addr.port());
+    let (klein, _ctx) = spawn_klein(&format!("port = 1\nhost = \"127.0.0.1\"\n\
+        [balancing]\nstrategy = \"round_robin\"\n[retries]\nmax_attempts = 1\n\
+        [circuit_breaker]\nconsecutive_failures = 3\ncooldown_ms = 60000\n{}{}",
+        server("failing", failing), server("good", good))).await;
+
+    let client = proxy::build_client(&Default::default(), None);
+    let get = |path: &str| {
+        let req = Request::get(format!("http://{klein}{path}")).body(Body::empty()).unwrap();
+        let client = client.clone();
+        async move {
+            let response = client.request(req).await.unwrap();
+            (response.status(), response.into_body().collect().await.unwrap().to_bytes())
+        }
+    };
+    let mut statuses = vec![];
+    for _ in 0..20 {
+        statuses.push(get("/neo").await.0);
+    }
+    assert_eq!(hits.load(Ordering::Acquire), 3);
+    assert_eq!(statuses.iter().filter(|c| **c == StatusCode::OK).count(), 17, "{statuses:?}");
+
+    let (status, body) = get("/breakers").await;
+    assert_eq!(status, StatusCode::OK);
+    let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    let state = |name: &str| body["message"].as_array().unwrap().iter()
+        .find(|c| c["name"] == name).unwrap()["state"].clone();
+    assert_eq!((state("breaker-failing"), state("breaker-good")), ("open".into(), "closed".into()));
+    assert_eq!(prometheus_stats::CIRCUIT_BREAKER_STATE.with_label_values(&["breaker-failing"]).get(), 2.0);
+}
+
+// Servers failing their health checks are ejected until they recover
+#[tokio::test]
+async fn test_health_checks() {
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::time::Duration;
+    use http_body_util::BodyExt;
+
+    let up = Arc::new(AtomicBool::new(true));
+    let u = up.clone();
+    let flaky = proxy::spawn_backend(Router::new()
+        .route("/heartbeat", get(move || {
+            let up = u.load(Ordering::Acquire);
+            async move { if up { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE } }
+        }))
+        .fallback(any(|| async { "flaky" }))).await;
+    let steady = proxy::spawn_backend(Router::new().fallback(any(|| async { "steady" }))).await;
+    let server = |name: &str, addr: SocketAddr| format!("[servers.{name}]\nhost = \"127.0.0.1\"\nport = {}\nname = \"health-{name}\"\n", addr.port());
+    let (klein, ctx) = spawn_klein(&format!("port = 1\nhost = \"127.0.0.1\"\n\
+        [balancing]\nstrategy = \"round_robin\"\n\
+        [health_check]\ninterval_ms = 20\nunhealthy_threshold = 2\nhealthy_threshold = 2\n{}{}",
+        server("flaky", flaky), server("steady", steady))).await;
+    tokio::spawn(heartbeat::health_checks(ctx.clone()));
+
+    let client = proxy::build_client(&Default::default(), None);
+    let get = |path: &str| {
+        let req = Request::get(format!("http://{klein}{path}")).body(Body::empty()).unwrap();
+        let client = client.clone();
+        async move { client.request(req).await.unwrap().into_body().collect().await.unwrap().to_bytes() }
+    };
+    let bodies = || async {
+        let mut bodies = vec![];
+        for _ in 0..6 {
+            bodies.push(String::from_utf8(get("/neo").await.to_vec()).unwrap());
+        }
+        bodies
+    };
+    assert!(bodies().await.contains(&"flaky".to_string()));
+
+    up.store(false, Ordering::Release);
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    assert!(bodies().await.iter().all(|c| c == "steady"));
+
+    let heartbeat: serde_json::Value = serde_json::from_slice(&get("/heartbeat").await).unwrap();
+    let flaky = heartbeat["server_hb"].as_array().unwrap().iter().find(|c| c["name"] == "health-flaky").unwrap().clone();
+    assert_eq!((flaky["healthy"].clone(), flaky["alive"].clone(), flaky["status_code"].clone()), (false.into(), false.into(), 503.into()));
+    assert!(heartbeat["last_check"].as_u64().unwrap() > 0);
+
+    up.store(true, Ordering::Release);
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    assert!(bodies().await.contains(&"flaky".to_string()));
+}
+
+// A server that passes its health checks but fails real requests is ejected
+#[tokio::test]
+async fn test_outlier_ejection() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    let hits = Arc::new(AtomicUsize::new(0));
+    let h = hits.clone();
+    let failing = proxy::spawn_backend(Router::new()
+        .route("/heartbeat", get(|| async { "ok" }))
+        .fallback(any(move || {
+            h.fetch_add(1, Ordering::AcqRel);
+            async { StatusCode::INTERNAL_SERVER_ERROR }
+        }))).await;
+    let good = proxy::spawn_backend(Router::new().fallback(any(|| async { "hello" }))).await;
+    let server = |name: &str, addr: SocketAddr| format!("[servers.{name}]\nhost = \"127.0.0.1\"\nport = {}\nname = \"outlier-{name}\"\n", addr.port());
+    let (klein, ctx) = spawn_klein(&format!("port = 1\nhost = \"127.0.0.1\"\n\
+        [balancing]\nstrategy = \"round_robin\"\n[retries]\nmax_attempts = 1\n\
+        [circuit_breaker]\nenabled = false\n[health_check]\ninterval_ms = 20\n\
+        [outlier_detection]\nconsecutive_5xx = 3\nmax_ejection_percent = 50\n{}{}",
+        server("failing", failing), server("good", good))).await;
+    tokio::spawn(heartbeat::health_checks(ctx.clone()));
+
+    let client = proxy::build_client(&Default::default(), None);
+    for _ in 0..20 {
+        let req = Request::get(format!("http://{klein}/neo")).body(Body::empty()).unwrap();
+        client.request(req).await.unwrap();
+    }
+    assert_eq!(hits.load(Ordering::Acquire), 3);
+    assert_eq!(prometheus_stats::OUTLIER_EJECTIONS.with_label_values(&["outlier-failing", "consecutive_5xx"]).get(), 1.0);
+
+    // the health checks still pass, it stays out of selection anyway
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    let pool = ctx.hash_server.read().unwrap();
+    let failing = pool.servers().iter().find(|c| c.name == "outlier-failing").unwrap();
+    assert!(failing.state.health.is_healthy());
+    assert_eq!(pool.eligible(&[]).len(), 1);
+}
+
+// /weight and /add refuse weight that does not fit on the ring
+#[tokio::test]
+async fn test_weight_capacity() {
+    let (klein, _ctx) = spawn_klein(&format!("port = 1\nhost = \"127.0.0.1\"\n\
+        [servers.static]\nhost = \"127.0.0.1\"\nport = 1\nname = \"static\"\n\
+        [ring]\nslots = 40\nvirtual_nodes = 10\n\
+        [replicas]\nruntime = \"local_process\"\ncommand = [\"sleep\", \"30\"]\nport_state_file = \"{}\"",
+        std::env::temp_dir().join(format!("klein-capacity-{}.json", std::process::id())).display())).await;
+    let client = proxy::build_client(&Default::default(), None);
+    let post = |path: &'static str, body: &'static str| {
+        let req = Request::post(format!("http://{klein}{path}")).header("content-type", "application/json").body(Body::from(body)).unwrap();
+        let client = client.clone();
+        async move { client.request(req).await.unwrap().status() }
+    };
+
+    assert_eq!(post("/weight", r#"{"name": "static", "weight": 5}"#).await, StatusCode::BAD_REQUEST);
+    assert_eq!(post("/weight", r#"{"name": "static", "weight": 3}"#).await, StatusCode::OK);
+    assert_eq!(post("/add", r#"{"n": 1, "weight": 2}"#).await, StatusCode::BAD_REQUEST);
+}
+
+// Concurrent /add requests for the same name start it only once
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_concurrent_add() {
+    let (klein, ctx) = spawn_klein(&format!("port = 1\nhost = \"127.0.0.1\"\n\
+        [replicas]\nruntime = \"local_process\"\ncommand = [\"sleep\", \"30\"]\nport_range = [19120, 19123]\nport_state_file = \"{}\"",
+        std::env::temp_dir().join(format!("klein-concurrent-{}.json", std::process::id())).display())).await;
+    let client = proxy::build_client(&Default::default(), None);
+    let add = || {
+        let req = Request::post(format!("http://{klein}/add")).header("content-type", "application/json")
+            .body(Body::from(r#"{"hostnames": ["twin"]}"#)).unwrap();
+        let client = client.clone();
+        async move { client.request(req).await.unwrap().status() }
+    };
+
+    let adds: Vec<_> = (0..8).map(|_| tokio::spawn(add())).collect();
+    let mut statuses = vec![];
+    for add in adds {
+        statuses.push(add.await.unwrap());
+    }
+    assert_eq!(statuses.iter().filter(|c| **c == StatusCode::CREATED).count(), 1, "{statuses:?}");
+    assert!(statuses.iter().all(|c| *c == StatusCode::CREATED || *c == StatusCode::BAD_REQUEST), "{statuses:?}");
+    let twins = ctx.hash_server.read().unwrap().servers().iter().filter(|c| c.name == "twin").count();
+    assert_eq!(twins, 1);
+    assert_eq!(ctx.ports.assigned().len(), 1);
+    ctx.runtime.stop("twin").await.unwrap();
+    ctx.ports.release("twin");
+}
+
+// /add and /rm start and remove `n` replicas, naming or picking the ones not given
+#[tokio::test]
+async fn test_scale_replicas() {
+    use http_body_util::BodyExt;
+
+    let (klein, ctx) = spawn_klein(&format!("port = 1\nhost = \"127.0.0.1\"\n\
+        [servers.static]\nhost = \"127.0.0.1\"\nport = 1\nname = \"static\"\n\
+        [replicas]\nruntime = \"local_process\"\ncommand = [\"sleep\", \"30\"]\nname_prefix = \"gen\"\nport_range = [19100, 19103]\n\
+        port_state_file = \"{}\"", std::env::temp_dir().join(format!("klein-scale-{}.json", std::process::id())).display())).await;
+    let client = proxy::build_client(&Default::default(), None);
+    let post = |path: &'static str, body: &'static str| {
+        let req = Request::post(format!("http://{klein}{path}")).header("content-type", "application/json").body(Body::from(body)).unwrap();
+        let client = client.clone();
+        async move {
+            let response = client.request(req).await.unwrap();
+            let status = response.status();
+            let body = response.into_body().collect().await.unwrap().to_bytes();
+            (status, serde_json::from_slice::<serde_json::Value>(&body).unwrap_or(serde_json::Value::Null))
+        }
+    };
+    let names = |body: &serde_json::Value| body["message"].as_array().unwrap().iter()
+        .map(|c| c["name"].as_str().unwrap().to_string())
+        .collect::<Vec<_>>();
+    let running = || ctx.hash_server.read().unwrap().servers().iter().map(|c| c.name.clone()).filter(|c| c != "static").collect::<Vec<_>>();
+
+    let (status, body) = post("/add", r#"{"n": 2, "hostnames": ["alpha"]}"#).await;
+    assert_eq!(status, StatusCode::CREATED);
+    let added = names(&body);
+    assert_eq!(added.len(), 2);
+    assert_eq!(added[0], "alpha");
+    assert!(added[1..].iter().all(|c| c.starts_with("gen-")), "{added:?}");
+    assert_eq!(running(), added);
+    assert_eq!(body["message"][0]["outcome"], "added");
+    assert_eq!(body["message"][0]["port"], 19100);
+
+    assert_eq!(post("/add", r#"{"n": 1, "hostnames": ["a", "b"]}"#).await.0, StatusCode::BAD_REQUEST);
+    assert_eq!(post("/add", r#"{"n": 2, "hostnames": ["a", "a"]}"#).await.0, StatusCode::BAD_REQUEST);
+    assert_eq!(post("/add", r#"{"n": 1, "hostnames": ["../images/create"]}"#).await.0, StatusCode::BAD_REQUEST);
+    assert_eq!(post("/rm", r#"{"n": 1, "hostnames": ["a?force=true"]}"#).await.0, StatusCode::BAD_REQUEST);
+    assert_eq!(post("/add", r#"{"n": 1, "hostnames": ["alpha"]}"#).await.0, StatusCode::BAD_REQUEST);
+    assert_eq!(post("/add", r#"{"n": 100000000}"#).await.0, StatusCode::BAD_REQUEST);
+
+    // the last port in the range is in use by someone else
+    let busy = std::net::TcpListener::bind((std::net::Ipv4Addr::UNSPECIFIED, 19103)).unwrap();
+    let (status, body) = post("/add", r#"{"n": 2}"#).await;
+    assert_eq!((status, body["status"].clone()), (StatusCode::MULTI_STATUS, "partial".into()));
+    assert_eq!(body["message"][1]["outcome"], "failed");
+    assert_eq!(running().len(), 3);
+    drop(busy);
+
+    assert_eq!(post("/rm", r#"{"n": 1, "hostnames": ["nobody"]}"#).await.0, StatusCode::NOT_FOUND);
+    assert_eq!(post("/rm", r#"{"n": 4}"#).await.0, StatusCode::BAD_REQUEST);
+    assert_eq!(post("/rm", r#"{"n": 1, "hostnames": ["static"]}"#).await.0, StatusCode::BAD_REQUEST);
+    let (status, body) = post("/rm", r#"{"n": 2, "hostnames": ["alpha"]}"#).await;
+    assert_eq!(status, StatusCode::OK);
+    let removed = names(&body);
+    assert_eq!(removed.len(), 2);
+    assert_eq!(removed[0], "alpha");
+    assert!(body["message"].as_array().unwrap().iter().all(|c| c["outcome"] == "removed"), "{body}");
+    assert_eq!(running().len(), 1);
+    assert!(running().iter().all(|c| !removed.contains(c)));
+
+    // a replica that cannot be stopped stays in the pool, the static server is never picked
+    let stuck = running()[0].clone();
+    ctx.runtime.stop(&stuck).await.unwrap();
+    assert_eq!(post("/rm", r#"{"n": 2}"#).await.0, StatusCode::BAD_REQUEST);
+    let (status, body) = post("/rm", r#"{"n": 1}"#).await;
+    assert_eq!(status, StatusCode::BAD_GATEWAY, "{body}");
+    assert_eq!(running(), vec![stuck.clone()]);
+    assert!(ctx.ports.assigned().contains_key(&stuck));
+    assert_eq!(ctx.hash_server.read().unwrap().servers().len(), 2);
+}
+
+// Replicas still running after a restart are taken back, or their ports released
+#[tokio::test]
+async fn test_restart_replicas() {
+    let listener = std::net::TcpListener::bind((std::net::Ipv4Addr::UNSPECIFIED, 0)).unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let state = std::env::temp_dir().join(format!("klein-restart-{}.json", std::process::id()));
+    let config = |runtime: &str| {
+        std::fs::write(&state, format!(r#"{{"old-replica": {port}, "static": {port}}}"#)).unwrap();
+        format!("port = 1\nhost = \"127.0.0.1\"\n[servers.static]\nhost = \"127.0.0.1\"\nport = 1\nname = \"static\"\n\
+            [replicas]\nruntime = \"{runtime}\"\ncommand = [\"sleep\", \"30\"]\nport_range = [{port}, {port}]\nport_state_file = \"{}\"",
+            state.display())
+    };
+
+    let (_, ctx) = spawn_klein(&config("docker_cli")).await;
+    let servers = ctx.hash_server.read().unwrap().server_containers();
+    let replica = servers.iter().find(|c| c.name == "old-replica").unwrap();
+    assert_eq!((servers.len(), replica.port), (2, port));
+    assert_eq!(ctx.ports.assigned(), std::collections::BTreeMap::from([("old-replica".to_string(), port)]));
+
+    // a local process of the old klein cannot be stopped by the new one
+    let (_, ctx) = spawn_klein(&config("local_process")).await;
+    assert_eq!(ctx.hash_server.read().unwrap().servers().len(), 1);
+    assert!(ctx.ports.assigned().is_empty());
+    drop(listener);
+    let _ = std::fs::remove_file(&state);
+}
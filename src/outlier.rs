@@ -0,0 +1,350 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use axum::http::StatusCode;
+use log::{info, warn};
+use serde::Deserialize;
+use crate::AppContext;
+use crate::config::SingleServer;
+use crate::consistent_hashing::ServerPool;
+use crate::prometheus_stats::{BACKEND_EJECTED, OUTLIER_EJECTIONS};
+
+fn default_enabled() -> bool {
+    true
+}
+
+fn default_consecutive_5xx() -> usize {
+    5
+}
+
+fn default_consecutive_gateway_failure() -> usize {
+    5
+}
+
+fn default_interval_ms() -> u64 {
+    10_000
+}
+
+fn default_base_ejection_time_ms() -> u64 {
+    30_000
+}
+
+fn default_max_ejection_time_ms() -> u64 {
+    300_000
+}
+
+fn default_max_ejection_percent() -> usize {
+    10
+}
+
+fn default_success_rate_minimum_hosts() -> usize {
+    5
+}
+
+fn default_success_rate_request_volume() -> usize {
+    100
+}
+
+fn default_success_rate_stdev_factor() -> f64 {
+    1.9
+}
+
+/// Ejecting backends that fail live traffic, set in `[outlier_detection]`
+///
+/// Works like Envoy's outlier detection: a backend is ejected right away after too many
+/// 5xx responses or gateway failures in a row, and at the end of every interval when
+/// its success rate is far below the mean of the pool
+#[derive(Deserialize)]
+#[derive(Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct OutlierConf {
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+    /// 5xx responses in a row that eject a backend, 0 disables this check
+    #[serde(default = "default_consecutive_5xx")]
+    pub consecutive_5xx: usize,
+    /// 502, 503 and 504 responses, failed connections and timeouts in a row that eject a
+    /// backend, 0 disables this check
+    #[serde(default = "default_consecutive_gateway_failure")]
+    pub consecutive_gateway_failure: usize,
+    /// Time between two success rate evaluations
+    #[serde(default = "default_interval_ms")]
+    pub interval_ms: u64,
+    /// The nth ejection of a backend lasts `base_ejection_time_ms * 2^(n-1)`, every interval
+    /// a backend spends in the pool lowers `n` by one again
+    #[serde(default = "default_base_ejection_time_ms")]
+    pub base_ejection_time_ms: u64,
+    #[serde(default = "default_max_ejection_time_ms")]
+    pub max_ejection_time_ms: u64,
+    /// At most this share of the backends is ejected at once, though always at least one
+    /// unless it is 0
+    #[serde(default = "default_max_ejection_percent")]
+    pub max_ejection_percent: usize,
+    /// Backends with enough requests in an interval needed to evaluate success rates
+    #[serde(default = "default_success_rate_minimum_hosts")]
+    pub success_rate_minimum_hosts: usize,
+    /// Requests a backend needs in an interval to take part in the evaluation
+    #[serde(default = "default_success_rate_request_volume")]
+    pub success_rate_request_volume: usize,
+    /// Backends below `mean - success_rate_stdev_factor * stdev` are ejected
+    #[serde(default = "default_success_rate_stdev_factor")]
+    pub success_rate_stdev_factor: f64,
+}
+
+impl Default for OutlierConf {
+    fn default() -> Self {
+        OutlierConf {
+            enabled: default_enabled(),
+            consecutive_5xx: default_consecutive_5xx(),
+            consecutive_gateway_failure: default_consecutive_gateway_failure(),
+            interval_ms: default_interval_ms(),
+            base_ejection_time_ms: default_base_ejection_time_ms(),
+            max_ejection_time_ms: default_max_ejection_time_ms(),
+            max_ejection_percent: default_max_ejection_percent(),
+            success_rate_minimum_hosts: default_success_rate_minimum_hosts(),
+            success_rate_request_volume: default_success_rate_request_volume(),
+            success_rate_stdev_factor: default_success_rate_stdev_factor(),
+        }
+    }
+}
+
+/// Why a backend was ejected, used as the `reason` label of the metric
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EjectReason {
+    Consecutive5xx,
+    ConsecutiveGatewayFailure,
+    SuccessRate,
+}
+
+impl EjectReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            EjectReason::Consecutive5xx => "consecutive_5xx",
+            EjectReason::ConsecutiveGatewayFailure => "consecutive_gateway_failure",
+            EjectReason::SuccessRate => "success_rate",
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Outlier {
+    consecutive_5xx: usize,
+    consecutive_gateway: usize,
+    /// Requests and successes in the current interval
+    requests: usize,
+    successes: usize,
+    ejected_until: Option<Instant>,
+    /// Ejections that count towards the next ejection time
+    ejections: u32,
+}
+
+/// Outcomes of live requests to a backend and whether it is ejected
+#[derive(Debug, Default)]
+pub struct OutlierState {
+    inner: Mutex<Outlier>,
+}
+
+impl OutlierState {
+    /// Whether the backend is ejected, a backend whose ejection time is over is returned to the pool
+    pub fn is_ejected(&self, name: &str) -> bool {
+        let mut outlier = self.inner.lock().unwrap();
+        match outlier.ejected_until {
+            Some(until) if Instant::now() >= until => {
+                info!("{} is no longer ejected", name);
+                outlier.ejected_until = None;
+                BACKEND_EJECTED.with_label_values(&[name]).set(0.0);
+                false
+            }
+            Some(_) => true,
+            None => false,
+        }
+    }
+
+    /// Count a response, returns why the backend should be ejected when a consecutive
+    /// failure threshold is reached
+    ///
+    /// `upstream_failure` is set when the backend could not be reached or timed out
+    pub fn observe(&self, conf: &OutlierConf, status: StatusCode, upstream_failure: bool) -> Option<EjectReason> {
+        let mut outlier = self.inner.lock().unwrap();
+        let server_error = upstream_failure || status.is_server_error();
+        let gateway = upstream_failure || matches!(status.as_u16(), 502..=504);
+
+        outlier.requests += 1;
+        if server_error {
+            outlier.consecutive_5xx += 1;
+        } else {
+            outlier.successes += 1;
+            outlier.consecutive_5xx = 0;
+        }
+        if gateway {
+            outlier.consecutive_gateway += 1;
+        } else {
+            outlier.consecutive_gateway = 0;
+        }
+        if outlier.ejected_until.is_some() {
+            None
+        } else if conf.consecutive_gateway_failure > 0 && outlier.consecutive_gateway >= conf.consecutive_gateway_failure {
+            Some(EjectReason::ConsecutiveGatewayFailure)
+        } else if conf.consecutive_5xx > 0 && outlier.consecutive_5xx >= conf.consecutive_5xx {
+            Some(EjectReason::Consecutive5xx)
+        } else {
+            None
+        }
+    }
+
+    fn eject(&self, name: &str, conf: &OutlierConf, reason: EjectReason) {
+        let mut outlier = self.inner.lock().unwrap();
+        outlier.ejections += 1;
+        let time = conf.base_ejection_time_ms
+            .saturating_mul(1u64 << (outlier.ejections - 1).min(32))
+            .min(conf.max_ejection_time_ms.max(conf.base_ejection_time_ms));
+        warn!("Ejecting {} for {}ms ({}), ejection number {}", name, time, reason.as_str(), outlier.ejections);
+        outlier.ejected_until = Some(Instant::now() + Duration::from_millis(time));
+        outlier.consecutive_5xx = 0;
+        outlier.consecutive_gateway = 0;
+        OUTLIER_EJECTIONS.with_label_values(&[name, reason.as_str()]).inc();
+        BACKEND_EJECTED.with_label_values(&[name]).set(1.0);
+    }
+
+    /// Start a new interval, returns the requests and successes of the one that ended
+    fn next_interval(&self, ejected: bool) -> (usize, usize) {
+        let mut outlier = self.inner.lock().unwrap();
+        if !ejected {
+            outlier.ejections = outlier.ejections.saturating_sub(1);
+        }
+        let counts = (outlier.requests, outlier.successes);
+        outlier.requests = 0;
+        outlier.successes = 0;
+        counts
+    }
+}
+
+/// Held while counting the ejected backends and ejecting one, so concurrent ejections
+/// cannot all see room under `max_ejection_percent`
+static EJECTING: Mutex<()> = Mutex::new(());
+
+/// Eject a backend unless that would eject more than `max_ejection_percent` of the pool,
+/// returns whether it was ejected
+pub fn try_eject(pool: &ServerPool, server: &SingleServer, conf: &OutlierConf, reason: EjectReason) -> bool {
+    let _ejecting = EJECTING.lock().unwrap();
+    if server.state.outlier.is_ejected(&server.name) {
+        return false;
+    }
+    let servers = pool.servers();
+    let ejected = servers.iter().filter(|c| c.state.outlier.is_ejected(&c.name)).count();
+    let allowed = if conf.max_ejection_percent == 0 { 0 } else { (servers.len() * conf.max_ejection_percent / 100).max(1) };
+    if ejected >= allowed {
+        warn!("Not ejecting {} ({}), {} of {} servers are ejected already", server.name, reason.as_str(), ejected, servers.len());
+        return false;
+    }
+    server.state.outlier.eject(&server.name, conf, reason);
+    true
+}
+
+/// Indices of the backends whose success rate is an outlier, given the requests and
+/// successes of every backend in the last interval
+fn success_rate_outliers(counts: &[(usize, usize)], conf: &OutlierConf) -> Vec<usize> {
+    let rates: Vec<(usize, f64)> = counts.iter().enumerate()
+        .filter(|(_, (requests, _))| *requests > 0 && *requests >= conf.success_rate_request_volume)
+        .map(|(i, (requests, successes))| (i, *successes as f64 / *requests as f64))
+        .collect();
+    if rates.is_empty() || rates.len() < conf.success_rate_minimum_hosts {
+        return vec![];
+    }
+    let mean = rates.iter().map(|(_, c)| c).sum::<f64>() / rates.len() as f64;
+    let variance = rates.iter().map(|(_, c)| (c - mean).powi(2)).sum::<f64>() / rates.len() as f64;
+    let threshold = mean - conf.success_rate_stdev_factor * variance.sqrt();
+
+    rates.into_iter().filter(|(_, c)| *c < threshold).map(|(i, _)| i).collect()
+}
+
+/// Evaluate the success rates of all backends at the end of every interval
+pub async fn outlier_detection(ctx: Arc<AppContext>) {
+    let conf = &ctx.app_config.outlier_detection;
+    let mut interval = tokio::time::interval(Duration::from_millis(conf.interval_ms));
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    // the first tick completes right away
+    interval.tick().await;
+
+    loop {
+        interval.tick().await;
+        let pool = ctx.hash_server.read().unwrap();
+        let counts: Vec<_> = pool.servers().iter()
+            .map(|c| {
+                let ejected = c.state.outlier.is_ejected(&c.name);
+                let counts = c.state.outlier.next_interval(ejected);
+                if ejected { (0, 0) } else { counts }
+            })
+            .collect();
+        for i in success_rate_outliers(&counts, conf) {
+            try_eject(&pool, &pool.servers()[i], conf, EjectReason::SuccessRate);
+        }
+    }
+}
+
+#[test]
+fn test_consecutive_failures() {
+    let conf = OutlierConf { consecutive_5xx: 3, consecutive_gateway_failure: 2, base_ejection_time_ms: 40, max_ejection_time_ms: 100, ..Default::default() };
+    let state = OutlierState::default();
+    let observe = |status: u16, upstream_failure: bool| state.observe(&conf, StatusCode::from_u16(status).unwrap(), upstream_failure);
+
+    assert_eq!(observe(500, false), None);
+    assert_eq!(observe(501, false), None);
+    assert_eq!(observe(500, false), Some(EjectReason::Consecutive5xx));
+    assert_eq!(observe(200, false), None);
+    assert_eq!(observe(503, false), None);
+    assert_eq!(observe(502, true), Some(EjectReason::ConsecutiveGatewayFailure));
+
+    // every ejection lasts twice as long as the one before, up to the maximum
+    let ejection = |state: &OutlierState| {
+        state.eject("outlier-test", &conf, EjectReason::Consecutive5xx);
+        let until = state.inner.lock().unwrap().ejected_until.unwrap();
+        (until - Instant::now()).as_millis()
+    };
+    let times: Vec<_> = (0..4).map(|_| ejection(&state)).collect();
+    assert!((35..=40).contains(&times[0]) && (75..=80).contains(&times[1]), "{times:?}");
+    assert!((95..=100).contains(&times[2]) && (95..=100).contains(&times[3]), "{times:?}");
+    assert!(state.is_ejected("outlier-test"));
+    // ejected backends are not ejected again
+    assert_eq!(observe(500, false), None);
+
+    std::thread::sleep(Duration::from_millis(110));
+    assert!(!state.is_ejected("outlier-test"));
+    state.next_interval(false);
+    assert_eq!(state.inner.lock().unwrap().ejections, 3);
+}
+
+#[test]
+fn test_success_rate_outliers() {
+    let conf = OutlierConf { success_rate_minimum_hosts: 3, success_rate_request_volume: 10, success_rate_stdev_factor: 1.0, ..Default::default() };
+
+    let counts = [(100, 99), (100, 98), (100, 100), (100, 60), (5, 0)];
+    assert_eq!(success_rate_outliers(&counts, &conf), [3]);
+    // too few backends with enough requests
+    assert!(success_rate_outliers(&counts[3..], &conf).is_empty());
+    // all equally bad
+    assert!(success_rate_outliers(&[(50, 10), (50, 10), (50, 10)], &conf).is_empty());
+
+    let pool = crate::consistent_hashing::test_pool(Default::default(), 3);
+    let conf = OutlierConf { max_ejection_percent: 50, ..conf };
+    let ejected = pool.servers().iter().filter(|c| try_eject(&pool, c, &conf, EjectReason::SuccessRate)).count();
+    assert_eq!(ejected, 1);
+    assert_eq!(pool.eligible(&[]).len(), 2);
+}
+
+// Backends failing at the same time never eject more than the allowed share
+#[test]
+fn test_concurrent_ejections() {
+    let pool = crate::consistent_hashing::test_pool(Default::default(), 10);
+    let conf = OutlierConf { max_ejection_percent: 30, ..Default::default() };
+    let barrier = std::sync::Barrier::new(pool.servers().len());
+
+    let ejected = std::thread::scope(|scope| {
+        let threads: Vec<_> = pool.servers().iter().map(|server| scope.spawn(|| {
+            barrier.wait();
+            try_eject(&pool, server, &conf, EjectReason::Consecutive5xx)
+        })).collect();
+        threads.into_iter().filter_map(|c| c.join().unwrap().then_some(())).count()
+    });
+    assert_eq!(ejected, 3);
+    assert_eq!(pool.eligible(&[]).len(), 7);
+}
@@ -0,0 +1,381 @@
+use std::sync::Arc;
+use log::warn;
+use serde::Deserialize;
+use crate::config::{MAX_WEIGHT, RingConf, SingleServer};
+use crate::consistent_hashing::SlotRing;
+use crate::hashers::{ring_hasher, RingHasher};
+
+/// Decides which server owns a request key
+pub trait Placement: Send + Sync {
+    /// Add a server, its weight scales the share of keys it owns
+    fn add(&mut self, server: &SingleServer);
+
+    /// Remove a server by name
+    fn remove(&mut self, name: &str);
+
+    /// Every server exactly once, in order of preference for a key, the first one owns the key
+    ///
+    /// The rest are the servers a request falls back to when the
+    /// owner cannot take it, e.g. with bounded loads
+    fn candidates<'a>(&'a self, key: &[u8]) -> Box<dyn Iterator<Item=&'a SingleServer> + 'a>;
+}
+
+/// Placement algorithms that can be selected with `ring.placement`
+#[derive(Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum PlacementKind {
+    /// Virtual servers on a ring of `ring.slots` slots
+    #[default]
+    Ring,
+    /// Maglev lookup table with (the next prime after) `ring.slots` entries
+    Maglev,
+    /// Rendezvous, or highest random weight, hashing
+    Rendezvous,
+    /// Jump consistent hash
+    Jump,
+}
+
+/// Create the placement selected in the ring config
+pub fn new_placement(ring: &RingConf) -> Box<dyn Placement> {
+    match ring.placement {
+        PlacementKind::Ring => Box::new(SlotRing::new(ring)),
+        PlacementKind::Maglev => Box::new(Maglev::new(ring)),
+        PlacementKind::Rendezvous => Box::new(Rendezvous::new(ring)),
+        PlacementKind::Jump => Box::new(Jump::new(ring)),
+    }
+}
+
+/// The splitmix64 finalizer, used to derive more hashes from one
+fn mix(mut x: u64) -> u64 {
+    x = x.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    x ^ (x >> 31)
+}
+
+fn is_prime(n: usize) -> bool {
+    n >= 2 && (2..).take_while(|c| c * c <= n).all(|c| !n.is_multiple_of(c))
+}
+
+/// Maglev hashing (Eisenbud et al., NSDI 2016)
+///
+/// Every server fills entries of a prime sized lookup table following its own
+/// permutation of the table, so each server ends up with an almost equal number
+/// of entries. Lookups are a single table read, the table is rebuilt on every change
+/// which moves slightly more keys than the ideal
+pub struct Maglev {
+    hasher: Arc<dyn RingHasher>,
+    table_size: usize,
+    servers: Vec<SingleServer>,
+    /// Index into `servers` for every table entry
+    table: Vec<usize>,
+}
+
+impl Maglev {
+    pub fn new(ring: &RingConf) -> Maglev {
+        let table_size = (ring.slots.max(2)..).find(|c| is_prime(*c)).unwrap();
+        Maglev { hasher: ring_hasher(ring), table_size, servers: vec![], table: vec![] }
+    }
+
+    fn populate(&mut self) {
+        self.table.clear();
+        if self.servers.is_empty() {
+            return;
+        }
+        // the table only depends on the set of servers, not the order they were added in
+        self.servers.sort_by(|a, b| a.name.cmp(&b.name));
+
+        let size = self.table_size;
+        let total_weight = self.servers.iter().fold(0usize, |sum, c| sum.saturating_add(c.weight.max(1)));
+        if total_weight > size {
+            warn!("Maglev table has {} entries for a total weight of {}, some servers get no keys", size, total_weight);
+        }
+        let permutations: Vec<(usize, usize)> = self.servers.iter().map(|c| {
+            let offset = self.hasher.hash(format!("{}#offset", c.name).as_bytes()) as usize % size;
+            let skip = self.hasher.hash(format!("{}#skip", c.name).as_bytes()) as usize % (size - 1) + 1;
+            (offset, skip)
+        }).collect();
+
+        let mut next = vec![0usize; self.servers.len()];
+        let mut table = vec![usize::MAX; size];
+        let mut filled = 0;
+
+        // servers take turns claiming their next free preferred entry, a server
+        // with weight w claims w entries per turn
+        'fill: loop {
+            for (i, server) in self.servers.iter().enumerate() {
+                let (offset, skip) = permutations[i];
+                for _ in 0..server.weight.max(1) {
+                    let mut entry = (offset + next[i] * skip) % size;
+                    while table[entry] != usize::MAX {
+                        next[i] += 1;
+                        entry = (offset + next[i] * skip) % size;
+                    }
+                    table[entry] = i;
+                    next[i] += 1;
+                    filled += 1;
+
+                    if filled == size {
+                        break 'fill;
+                    }
+                }
+            }
+        }
+        self.table = table;
+    }
+}
+
+impl Placement for Maglev {
+    fn add(&mut self, server: &SingleServer) {
+        self.servers.push(server.clone());
+        self.populate();
+    }
+
+    fn remove(&mut self, name: &str) {
+        self.servers.retain(|c| c.name != name);
+        self.populate();
+    }
+
+    // The table entry of the key, then the servers of the following entries, each once
+    fn candidates<'a>(&'a self, key: &[u8]) -> Box<dyn Iterator<Item=&'a SingleServer> + 'a> {
+        if self.table.is_empty() {
+            return Box::new(std::iter::empty());
+        }
+        let start = (self.hasher.hash(key) % self.table.len() as u64) as usize;
+        let mut seen = vec![false; self.servers.len()];
+
+        Box::new(self.table[start..]
+            .iter()
+            .chain(self.table[..start].iter())
+            .filter(move |i| !std::mem::replace(&mut seen[**i], true))
+            .take(self.servers.len())
+            .map(|i| &self.servers[*i]))
+    }
+}
+
+/// Rendezvous, or highest random weight, hashing (Thaler and Ravishankar, 1998)
+///
+/// Every server scores the key and the highest score wins. Only keys of an added or
+/// removed server move, at the price of scoring every server on each lookup.
+/// Weights use the logarithmic method, `-weight / ln(hash)`
+pub struct Rendezvous {
+    hasher: Arc<dyn RingHasher>,
+    /// Servers with the hash of their name
+    servers: Vec<(SingleServer, u64)>,
+}
+
+impl Rendezvous {
+    pub fn new(ring: &RingConf) -> Rendezvous {
+        Rendezvous { hasher: ring_hasher(ring), servers: vec![] }
+    }
+
+    fn score(server: &SingleServer, server_hash: u64, key_hash: u64) -> f64 {
+        // uniform in (0, 1)
+        let unit = ((mix(server_hash ^ key_hash) >> 11) as f64 + 0.5) / (1u64 << 53) as f64;
+        -(server.weight.max(1) as f64) / unit.ln()
+    }
+}
+
+impl Placement for Rendezvous {
+    fn add(&mut self, server: &SingleServer) {
+        let server_hash = self.hasher.hash(server.name.as_bytes());
+        self.servers.push((server.clone(), server_hash));
+    }
+
+    fn remove(&mut self, name: &str) {
+        self.servers.retain(|(c, _)| c.name != name);
+    }
+
+    // Servers by descending score
+    fn candidates<'a>(&'a self, key: &[u8]) -> Box<dyn Iterator<Item=&'a SingleServer> + 'a> {
+        let key_hash = self.hasher.hash(key);
+        let mut scored: Vec<(f64, &SingleServer)> = self.servers
+            .iter()
+            .map(|(c, h)| (Self::score(c, *h, key_hash), c))
+            .collect();
+        scored.sort_by(|a, b| b.0.total_cmp(&a.0));
+
+        Box::new(scored.into_iter().map(|(_, c)| c))
+    }
+}
+
+/// Jump consistent hash (Lamping and Veach, 2014)
+///
+/// Needs no memory besides the list of buckets and spreads keys evenly, but buckets
+/// are numbered so only adding or removing the most recently added server is
+/// cheap, removing any other server renumbers the buckets after it.
+/// A server with weight w gets w buckets
+pub struct Jump {
+    hasher: Arc<dyn RingHasher>,
+    servers: Vec<SingleServer>,
+    /// Index into `servers` for every bucket
+    buckets: Vec<usize>,
+}
+
+impl Jump {
+    pub fn new(ring: &RingConf) -> Jump {
+        Jump { hasher: ring_hasher(ring), servers: vec![], buckets: vec![] }
+    }
+
+    fn bucket(mut key: u64, num_buckets: usize) -> usize {
+        let (mut b, mut j) = (-1i64, 0i64);
+        while j < num_buckets as i64 {
+            b = j;
+            key = key.wrapping_mul(2862933555777941757).wrapping_add(1);
+            j = ((b + 1) as f64 * ((1u64 << 31) as f64 / ((key >> 33) + 1) as f64)) as i64;
+        }
+        b as usize
+    }
+
+    fn rebuild_buckets(&mut self) {
+        self.buckets = self.servers
+            .iter()
+            .enumerate()
+            .flat_map(|(i, c)| std::iter::repeat_n(i, c.weight.clamp(1, MAX_WEIGHT)))
+            .collect();
+    }
+}
+
+impl Placement for Jump {
+    fn add(&mut self, server: &SingleServer) {
+        self.servers.push(server.clone());
+        self.rebuild_buckets();
+    }
+
+    fn remove(&mut self, name: &str) {
+        self.servers.retain(|c| c.name != name);
+        self.rebuild_buckets();
+    }
+
+    // The bucket of the key, then buckets of rehashed keys, then the remaining servers in order
+    fn candidates<'a>(&'a self, key: &[u8]) -> Box<dyn Iterator<Item=&'a SingleServer> + 'a> {
+        let (servers, buckets) = (&self.servers, &self.buckets);
+        let key_hash = self.hasher.hash(key);
+        let max_attempts = 4 * buckets.len() as u64;
+
+        let mut seen = vec![false; servers.len()];
+        let mut remaining = servers.len();
+        let mut attempt = 0u64;
+        let mut fallback = 0usize;
+
+        Box::new(std::iter::from_fn(move || {
+            if remaining == 0 {
+                return None;
+            }
+            while attempt < max_attempts {
+                let hash = if attempt == 0 { key_hash } else { mix(key_hash.wrapping_add(attempt)) };
+                attempt += 1;
+                let i = buckets[Self::bucket(hash, buckets.len())];
+                if !std::mem::replace(&mut seen[i], true) {
+                    remaining -= 1;
+                    return Some(&servers[i]);
+                }
+            }
+            while seen[fallback] {
+                fallback += 1;
+            }
+            seen[fallback] = true;
+            remaining -= 1;
+            Some(&servers[fallback])
+        }))
+    }
+}
+
+#[cfg(test)]
+const ALL_PLACEMENTS: [PlacementKind; 4] = [PlacementKind::Ring, PlacementKind::Maglev, PlacementKind::Rendezvous, PlacementKind::Jump];
+
+#[cfg(test)]
+fn test_placement(kind: PlacementKind, servers: &[SingleServer]) -> Box<dyn Placement> {
+    let ring = RingConf { placement: kind, slots: 65537, virtual_nodes: 200, ..RingConf::default() };
+    let mut placement = new_placement(&ring);
+    for server in servers {
+        placement.add(server);
+    }
+    placement
+}
+
+#[cfg(test)]
+fn owners(placement: &dyn Placement, num_keys: u64) -> Vec<String> {
+    (0..num_keys).map(|c| placement.candidates(&c.to_le_bytes()).next().unwrap().name.clone()).collect()
+}
+
+#[cfg(test)]
+fn moved_fraction(before: &[String], after: &[String]) -> f64 {
+    before.iter().zip(after).filter(|(b, a)| b != a).count() as f64 / before.len() as f64
+}
+
+// Report distribution skew and disruption on membership change for every placement
+//
+// Run with `cargo test placement -- --nocapture` to see the table
+#[test]
+fn test_compare_placements() {
+    const KEYS: u64 = 50_000;
+    const SERVERS: usize = 10;
+    let servers = crate::consistent_hashing::test_pool(RingConf::default(), SERVERS + 1).server_containers();
+    let (initial, extra) = servers.split_at(SERVERS);
+
+    println!("{:<12} {:>8} {:>12} {:>18} {:>16}", "placement", "skew", "add moved", "remove mid moved", "remove last moved");
+    for kind in ALL_PLACEMENTS {
+        let mut placement = test_placement(kind, initial);
+        let before = owners(placement.as_ref(), KEYS);
+
+        // skew is the most loaded server relative to a perfectly even split
+        let max_share = initial.iter().map(|s| before.iter().filter(|c| **c == s.name).count()).max().unwrap();
+        let skew = max_share as f64 / (KEYS as f64 / SERVERS as f64);
+
+        // disruption is the fraction of keys that moved relative to the minimum that has to move
+        placement.add(&extra[0]);
+        let add = moved_fraction(&before, &owners(placement.as_ref(), KEYS)) * (SERVERS + 1) as f64;
+        placement.remove(&extra[0].name);
+
+        placement.remove(&initial[SERVERS / 2].name);
+        let remove_mid = moved_fraction(&before, &owners(placement.as_ref(), KEYS)) * SERVERS as f64;
+        placement.add(&initial[SERVERS / 2]);
+
+        let placement = test_placement(kind, &initial[..SERVERS - 1]);
+        let remove_last = moved_fraction(&before, &owners(placement.as_ref(), KEYS)) * SERVERS as f64;
+
+        println!("{:<12} {:>8.3} {:>12.3} {:>18.3} {:>16.3}", format!("{kind:?}"), skew, add, remove_mid, remove_last);
+
+        assert!(skew < 1.3, "{kind:?} skew {skew:.3}");
+        assert!(add < 1.5, "{kind:?} moved {add:.3}x the minimum when adding");
+        assert!(remove_last < 1.5, "{kind:?} moved {remove_last:.3}x the minimum when removing the last server");
+        if kind != PlacementKind::Jump {
+            assert!(remove_mid < 1.5, "{kind:?} moved {remove_mid:.3}x the minimum when removing a server");
+        }
+    }
+}
+
+// Candidates are every server exactly once, starting with the owner
+#[test]
+fn test_candidates_cover_servers() {
+    let mut servers = crate::consistent_hashing::test_pool(RingConf::default(), 7).server_containers();
+    servers[2].weight = 3;
+
+    for kind in ALL_PLACEMENTS {
+        let placement = test_placement(kind, &servers);
+        for key in 0..200u64 {
+            let names: Vec<String> = placement.candidates(&key.to_le_bytes()).map(|c| c.name.clone()).collect();
+            for server in &servers {
+                assert_eq!(names.iter().filter(|c| **c == server.name).count(), 1, "{kind:?}: {names:?}");
+            }
+            assert_eq!(names.len(), servers.len(), "{kind:?}: {names:?}");
+        }
+    }
+    assert!(test_placement(PlacementKind::Maglev, &[]).candidates(b"key").next().is_none());
+    assert!(test_placement(PlacementKind::Jump, &[]).candidates(b"key").next().is_none());
+}
+
+#[test]
+fn test_weighted_placements() {
+    const KEYS: u64 = 40_000;
+    let mut servers = crate::consistent_hashing::test_pool(RingConf::default(), 4).server_containers();
+    servers[0].weight = 3;
+
+    for kind in ALL_PLACEMENTS {
+        let owners = owners(test_placement(kind, &servers).as_ref(), KEYS);
+        let share = owners.iter().filter(|c| **c == servers[0].name).count() as f64 / KEYS as f64;
+        assert!((share - 0.5).abs() < 0.05, "{kind:?}: weight 3 of 6 got {share:.3} of the keys");
+    }
+}
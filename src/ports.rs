@@ -0,0 +1,137 @@
+use std::collections::BTreeMap;
+use std::net::{Ipv4Addr, TcpListener};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use log::{info, warn};
+
+/// Hands out ports for replicas from a range and remembers which replica has which
+///
+/// A port is only handed out when nothing listens on it. With a state file the
+/// assignments survive restarts, ports whose replica is gone by then are freed
+pub struct PortAllocator {
+    range: (u16, u16),
+    state_file: Option<PathBuf>,
+    /// Replica name to port
+    ports: Mutex<BTreeMap<String, u16>>,
+}
+
+/// Whether nothing listens on `port`
+fn is_available(port: u16) -> bool {
+    TcpListener::bind((Ipv4Addr::UNSPECIFIED, port)).is_ok()
+}
+
+fn load(path: &Path) -> BTreeMap<String, u16> {
+    match std::fs::read_to_string(path) {
+        Ok(contents) => serde_json::from_str(&contents).unwrap_or_else(|e| {
+            warn!("Ignoring port state in {}, it could not be parsed: {}", path.display(), e);
+            BTreeMap::new()
+        }),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => BTreeMap::new(),
+        Err(e) => {
+            warn!("Could not read port state from {}: {}", path.display(), e);
+            BTreeMap::new()
+        }
+    }
+}
+
+impl PortAllocator {
+    pub fn new(range: (u16, u16), state_file: Option<PathBuf>) -> PortAllocator {
+        let mut ports = state_file.as_deref().map(load).unwrap_or_default();
+        let saved = ports.len();
+        // a port that is free again lost its replica while klein was down
+        ports.retain(|name, port| {
+            let keep = (range.0..=range.1).contains(port) && !is_available(*port);
+            if !keep {
+                info!("Releasing port {} of {}, nothing listens on it anymore", port, name);
+            }
+            keep
+        });
+        let allocator = PortAllocator { range, state_file, ports: Mutex::new(ports) };
+        let ports = allocator.ports.lock().unwrap();
+        if ports.len() != saved {
+            allocator.persist(&ports);
+        }
+        drop(ports);
+        allocator
+    }
+
+    /// Port for the replica `name`, the one it already has or the first free one in the range
+    pub fn allocate(&self, name: &str) -> Option<u16> {
+        let mut ports = self.ports.lock().unwrap();
+        if let Some(port) = ports.get(name) {
+            return Some(*port);
+        }
+        let taken: Vec<u16> = ports.values().copied().collect();
+        let port = (self.range.0..=self.range.1).find(|c| !taken.contains(c) && is_available(*c))?;
+        ports.insert(name.to_string(), port);
+        self.persist(&ports);
+        Some(port)
+    }
+
+    /// Every replica with a port, by name
+    pub fn assigned(&self) -> BTreeMap<String, u16> {
+        self.ports.lock().unwrap().clone()
+    }
+
+    /// Number of ports in the range no replica has, some may still be in use by others
+    pub fn unassigned(&self) -> usize {
+        (self.range.1 - self.range.0) as usize + 1 - self.ports.lock().unwrap().len()
+    }
+
+    /// Free the port of the replica `name`
+    pub fn release(&self, name: &str) {
+        let mut ports = self.ports.lock().unwrap();
+        if ports.remove(name).is_some() {
+            self.persist(&ports);
+        }
+    }
+
+    /// Write the state file, through a temporary file so it is never left half written
+    fn persist(&self, ports: &BTreeMap<String, u16>) {
+        let Some(path) = &self.state_file else {
+            return;
+        };
+        let tmp = path.with_extension("tmp");
+        let written = serde_json::to_vec_pretty(ports)
+            .map_err(std::io::Error::other)
+            .and_then(|c| std::fs::write(&tmp, c))
+            .and_then(|_| std::fs::rename(&tmp, path));
+        if let Err(e) = written {
+            warn!("Could not save port state to {}: {}", path.display(), e);
+        }
+    }
+}
+
+#[test]
+fn test_port_allocator() {
+    // four free ports in a row, below the ephemeral ports other tests bind to
+    let busy = (20000..32000).step_by(4)
+        .find(|c| (*c..*c + 4).all(is_available))
+        .expect("no four free ports in a row between 20000 and 32000");
+    // the range is the busy port and the three after it
+    let listener = TcpListener::bind((Ipv4Addr::UNSPECIFIED, busy)).unwrap();
+    let state = std::env::temp_dir().join(format!("klein-ports-{}.json", std::process::id()));
+    let _ = std::fs::remove_file(&state);
+    let allocator = PortAllocator::new((busy, busy + 3), Some(state.clone()));
+
+    // the busy port is skipped, every replica keeps its port
+    assert_eq!(allocator.allocate("a"), Some(busy + 1));
+    assert_eq!(allocator.allocate("b"), Some(busy + 2));
+    assert_eq!(allocator.allocate("a"), Some(busy + 1));
+    assert_eq!(allocator.allocate("c"), Some(busy + 3));
+    assert_eq!(allocator.allocate("d"), None);
+
+    // released ports are reused
+    allocator.release("a");
+    assert_eq!(allocator.allocate("d"), Some(busy + 1));
+    assert_eq!(load(&state), BTreeMap::from([("b".to_string(), busy + 2), ("c".to_string(), busy + 3), ("d".to_string(), busy + 1)]));
+
+    // after a restart only ports that are still in use are kept
+    let replica = TcpListener::bind((Ipv4Addr::UNSPECIFIED, busy + 2)).unwrap();
+    let allocator = PortAllocator::new((busy, busy + 3), Some(state.clone()));
+    assert_eq!(load(&state), BTreeMap::from([("b".to_string(), busy + 2)]));
+    assert_eq!(allocator.allocate("b"), Some(busy + 2));
+    assert_eq!(allocator.allocate("e"), Some(busy + 1));
+    drop((replica, listener));
+    let _ = std::fs::remove_file(&state);
+}
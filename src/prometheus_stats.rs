@@ -1,6 +1,6 @@
 use lazy_static::lazy_static;
-use prometheus::{CounterVec, Histogram, histogram_opts, labels, opts, register_counter, register_counter_vec, register_gauge, register_histogram, register_histogram_vec};
-use prometheus::{Counter, Encoder, Gauge, HistogramVec, TextEncoder};
+use prometheus::{CounterVec, labels, opts, register_counter, register_counter_vec, register_gauge, register_gauge_vec, register_histogram_vec};
+use prometheus::{Counter, Gauge, GaugeVec, HistogramVec};
 
 lazy_static! {
     pub static ref HTTP_COUNTER: Counter = register_counter!(opts!(
@@ -27,4 +27,74 @@ lazy_static! {
         "Number of requests in a particular time",
         &["handler","status_code"]
     ).unwrap();
+
+    pub static ref BACKEND_IN_FLIGHT: GaugeVec = register_gauge_vec!(
+        "klein_backend_in_flight_requests",
+        "Number of requests currently proxied to a backend",
+        &["handler"]
+    ).unwrap();
+
+    pub static ref BOUNDED_LOAD_FORWARDS: CounterVec = register_counter_vec!(
+        "klein_bounded_load_forwards_total",
+        "Number of requests moved past a backend that was over its bounded load capacity",
+        &["handler"]
+    ).unwrap();
+
+    pub static ref BACKEND_LATENCY_EWMA: GaugeVec = register_gauge_vec!(
+        "klein_backend_latency_ewma_seconds",
+        "Peak EWMA of backend response latency as of the last response",
+        &["handler"]
+    ).unwrap();
+
+    pub static ref UPSTREAM_TIMEOUTS: CounterVec = register_counter_vec!(
+        "klein_upstream_timeouts_total",
+        "Number of requests to a backend that timed out, by the timeout that expired",
+        &["handler", "timeout"]
+    ).unwrap();
+
+    pub static ref RETRIES: CounterVec = register_counter_vec!(
+        "klein_retries_total",
+        "Number of requests retried on another backend, by the backend that failed",
+        &["handler"]
+    ).unwrap();
+
+    pub static ref RETRY_BUDGET_EXHAUSTED: Counter = register_counter!(
+        "klein_retry_budget_exhausted_total",
+        "Number of failed requests that were not retried because the retry budget was used up"
+    ).unwrap();
+
+    pub static ref BACKEND_HEALTHY: GaugeVec = register_gauge_vec!(
+        "klein_backend_healthy",
+        "Whether a backend passes its health checks and is selected, 1 healthy, 0 ejected",
+        &["handler"]
+    ).unwrap();
+
+    pub static ref BACKEND_EJECTED: GaugeVec = register_gauge_vec!(
+        "klein_backend_ejected",
+        "Whether a backend is ejected by outlier detection, 1 ejected, 0 selected",
+        &["handler"]
+    ).unwrap();
+
+    pub static ref OUTLIER_EJECTIONS: CounterVec = register_counter_vec!(
+        "klein_outlier_ejections_total",
+        "Number of times a backend was ejected by outlier detection, by the reason",
+        &["handler", "reason"]
+    ).unwrap();
+
+    pub static ref REPLICAS_REPLACED: Counter = register_counter!(
+        "klein_replicas_replaced_total",
+        "Number of dead replicas replaced by a new container"
+    ).unwrap();
+
+    pub static ref CIRCUIT_BREAKER_STATE: GaugeVec = register_gauge_vec!(
+        "klein_circuit_breaker_state",
+        "State of a backend's circuit breaker, 0 closed, 1 half open, 2 open",
+        &["handler"]
+    ).unwrap();
+
+    pub static ref CIRCUIT_BREAKER_TRIPS: CounterVec = register_counter_vec!(
+        "klein_circuit_breaker_trips_total",
+        "Number of times a backend's circuit breaker opened",
+        &["handler"]
+    ).unwrap();
 }
@@ -0,0 +1,332 @@
+use std::error::Error;
+use std::time::{Duration, Instant};
+use axum::body::Body;
+use axum::extract::Request;
+use axum::http::{header, HeaderMap, HeaderName, StatusCode, Uri, Version};
+use axum::response::Response;
+use http_body_util::{LengthLimitError, Limited};
+use hyper_util::client::legacy::Client;
+use hyper_util::client::legacy::connect::HttpConnector;
+use hyper_util::rt::{TokioExecutor, TokioTimer};
+use log::{trace, warn};
+use serde::Deserialize;
+use crate::config::SingleServer;
+use crate::forwarding::{add_forwarding_headers, ForwardingConf};
+use crate::timeouts::{Deadlines, TimeoutConf, TimeoutKind};
+use crate::prometheus_stats::HTTP_RESPONSE_STATUS;
+
+/// Client used to proxy requests to a backend, every backend has its own pool
+pub type HttpClient = Client<HttpConnector, Body>;
+
+/// Idle connections kept per backend when `max_idle` is not set
+const DEFAULT_MAX_IDLE: usize = 32;
+/// Seconds an idle connection is kept when `idle_timeout_secs` is not set
+const DEFAULT_IDLE_TIMEOUT_SECS: u64 = 90;
+
+/// Connection pool settings, set globally in `[pool]` and per server in `[servers.<key>.pool]`
+#[derive(Deserialize)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct PoolConf {
+    /// Idle keep-alive connections kept open to a backend
+    pub max_idle: Option<usize>,
+    /// Seconds an idle connection is kept open before it is closed
+    pub idle_timeout_secs: Option<u64>,
+}
+
+impl PoolConf {
+    /// These settings, with the ones that are not set taken from `fallback`
+    pub fn or(&self, fallback: &PoolConf) -> PoolConf {
+        PoolConf {
+            max_idle: self.max_idle.or(fallback.max_idle),
+            idle_timeout_secs: self.idle_timeout_secs.or(fallback.idle_timeout_secs),
+        }
+    }
+}
+
+/// Marks a response klein generated because the backend could not be reached,
+/// the connection failed or the backend did not answer in time
+#[derive(Debug, Clone, Copy)]
+pub struct UpstreamFailure;
+
+/// Maximum body sizes in bytes, set in `[limits]`, unset means unlimited
+#[derive(Deserialize)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct LimitsConf {
+    /// Larger request bodies are rejected with `413 Payload Too Large`
+    pub max_request_body: Option<usize>,
+    /// Larger responses are replaced with `502 Bad Gateway` when the backend announces
+    /// their length, otherwise the response is cut off once the limit is reached
+    pub max_response_body: Option<usize>,
+}
+
+/// Create a keep-alive client with its own connection pool
+pub fn build_client(pool: &PoolConf, connect_timeout: Option<Duration>) -> HttpClient {
+    let mut connector = HttpConnector::new();
+    connector.set_nodelay(true);
+    connector.set_connect_timeout(connect_timeout);
+
+    Client::builder(TokioExecutor::new())
+        .pool_timer(TokioTimer::new())
+        .pool_max_idle_per_host(pool.max_idle.unwrap_or(DEFAULT_MAX_IDLE))
+        .pool_idle_timeout(Duration::from_secs(pool.idle_timeout_secs.unwrap_or(DEFAULT_IDLE_TIMEOUT_SECS)))
+        .build(connector)
+}
+
+fn error_response(status: StatusCode, message: &str) -> Response {
+    Response::builder().status(status).body(Body::from(message.to_string())).unwrap()
+}
+
+fn upstream_failure(status: StatusCode, message: &str) -> Response {
+    let mut response = error_response(status, message);
+    response.extensions_mut().insert(UpstreamFailure);
+    response
+}
+
+fn content_length(headers: &HeaderMap) -> Option<usize> {
+    headers.get(header::CONTENT_LENGTH)?.to_str().ok()?.parse().ok()
+}
+
+/// Headers that only apply to a single connection, RFC 7230 section 6.1
+///
+/// `proxy-connection` is not standard but still sent by some clients
+const HOP_BY_HOP: [HeaderName; 9] = [
+    header::CONNECTION,
+    HeaderName::from_static("keep-alive"),
+    HeaderName::from_static("proxy-connection"),
+    header::PROXY_AUTHENTICATE,
+    header::PROXY_AUTHORIZATION,
+    header::TE,
+    header::TRAILER,
+    header::TRANSFER_ENCODING,
+    header::UPGRADE,
+];
+
+/// Remove hop-by-hop headers, including the ones named in `Connection`
+///
+/// Values that are not valid UTF-8 are compared as bytes, invalid names are ignored
+pub fn strip_hop_by_hop(headers: &mut HeaderMap) {
+    let named: Vec<HeaderName> = headers
+        .get_all(header::CONNECTION)
+        .iter()
+        .flat_map(|c| c.as_bytes().split(|b| *b == b','))
+        .filter_map(|c| HeaderName::from_bytes(c.trim_ascii()).ok())
+        .collect();
+
+    for name in named.iter().chain(HOP_BY_HOP.iter()) {
+        headers.remove(name);
+    }
+}
+
+/// Whether `error` or any error that caused it matches
+fn caused_by(error: &(dyn Error + 'static), matches: impl Fn(&(dyn Error + 'static)) -> bool) -> bool {
+    let mut source = Some(error);
+    while let Some(e) = source {
+        if matches(e) {
+            return true;
+        }
+        source = e.source();
+    }
+    false
+}
+
+/// Whether the request failed because its body went over the limit while streaming
+fn is_length_limit(error: &(dyn Error + 'static)) -> bool {
+    caused_by(error, |c| c.is::<LengthLimitError>())
+}
+
+/// Whether connecting to the backend took longer than the connect timeout
+fn is_connect_timeout(error: &hyper_util::client::legacy::Error) -> bool {
+    error.is_connect() && caused_by(error, |c| c.downcast_ref::<std::io::Error>().is_some_and(|c| c.kind() == std::io::ErrorKind::TimedOut))
+}
+
+/// Proxy a request to a backend
+///
+/// Bodies are streamed in both directions without being buffered, the latency until
+/// the response headers arrive is recorded in the server's peak EWMA, a request that
+/// failed to get them counts as a slow response. A timeout that
+/// expires before the response headers arrive is answered with `504 Gateway Timeout`,
+/// one that expires later cuts the response body off
+pub async fn handle_request(client: &HttpClient, server: &SingleServer, limits: &LimitsConf,
+                            forwarding: &ForwardingConf, timeouts: &TimeoutConf, mut req: Request) -> Response {
+    if let Some(limit) = limits.max_request_body {
+        if content_length(req.headers()).is_some_and(|c| c > limit) {
+            return error_response(StatusCode::PAYLOAD_TOO_LARGE, "request body too large");
+        }
+        // bodies without a length, or lying about it, are cut off while streaming
+        req = req.map(|c| Body::new(Limited::new(c, limit)));
+    }
+
+    let path = req.uri().path_and_query().map(|c| c.as_str()).unwrap_or("/");
+    let uri = match format!("http://{}:{}{}", server.host, server.port, path).parse::<Uri>() {
+        Ok(uri) => uri,
+        Err(e) => {
+            warn!("Invalid backend url for server {}: {}", server.name, e);
+            return error_response(StatusCode::BAD_GATEWAY, "invalid backend address");
+        }
+    };
+    trace!("URL {}", uri);
+    *req.uri_mut() = uri;
+    // after stripping, so a client cannot drop them by listing them in `Connection`
+    strip_hop_by_hop(req.headers_mut());
+    add_forwarding_headers(forwarding, &mut req);
+    // the client sets the backend's address as host
+    req.headers_mut().remove(header::HOST);
+    // the client speaks HTTP/1.1 to backends whatever the client spoke to us
+    *req.version_mut() = Version::HTTP_11;
+
+    let start = Instant::now();
+    let deadlines = Deadlines::start(timeouts);
+
+    let result = match deadlines.headers(client.request(req)).await {
+        Ok(result) => result,
+        Err(kind) => {
+            kind.record(&server.name);
+            server.state.observe_failure(&server.name, start.elapsed());
+            return upstream_failure(StatusCode::GATEWAY_TIMEOUT, "backend server timed out");
+        }
+    };
+    match result {
+        Ok(mut response) => {
+            strip_hop_by_hop(response.headers_mut());
+            let elapsed = start.elapsed();
+            server.state.observe_latency(&server.name, elapsed);
+            HTTP_RESPONSE_STATUS.with_label_values(&[response.status().as_str(), server.name.as_str()]).inc();
+            trace!("Took {:?} ms to get response", elapsed.as_millis());
+
+            match limits.max_response_body {
+                Some(limit) if content_length(response.headers()).is_some_and(|c| c > limit) => {
+                    warn!("Response of {} is larger than {} bytes, not forwarding it", server.name, limit);
+                    error_response(StatusCode::BAD_GATEWAY, "backend response too large")
+                }
+                Some(limit) => response.map(|c| deadlines.body(Body::new(Limited::new(c, limit)), &server.name)),
+                None => response.map(|c| deadlines.body(Body::new(c), &server.name)),
+            }
+        }
+        Err(e) if is_length_limit(&e) => {
+            error_response(StatusCode::PAYLOAD_TOO_LARGE, "request body too large")
+        }
+        Err(e) if is_connect_timeout(&e) => {
+            TimeoutKind::Connect.record(&server.name);
+            server.state.observe_failure(&server.name, start.elapsed());
+            upstream_failure(StatusCode::GATEWAY_TIMEOUT, "backend server timed out")
+        }
+        Err(e) => {
+            warn!("Error occurred when making request to {}: {:?}", server.name, e);
+            server.state.observe_failure(&server.name, start.elapsed());
+            upstream_failure(StatusCode::BAD_GATEWAY, "could not reach backend server")
+        }
+    }
+}
+
+#[test]
+fn test_strip_hop_by_hop() {
+    let mut headers = HeaderMap::new();
+    headers.insert("connection", "keep-alive, X-Internal ,, bad name".parse().unwrap());
+    headers.append("connection", axum::http::HeaderValue::from_bytes(b"x-caf\xe9, x-other").unwrap());
+    headers.insert("keep-alive", "timeout=5".parse().unwrap());
+    headers.insert("transfer-encoding", "chunked".parse().unwrap());
+    headers.insert("x-internal", "1".parse().unwrap());
+    headers.insert("x-other", "1".parse().unwrap());
+    headers.insert("content-type", "application/json".parse().unwrap());
+    headers.append("set-cookie", "a=1".parse().unwrap());
+    headers.append("set-cookie", "b=2".parse().unwrap());
+
+    strip_hop_by_hop(&mut headers);
+    let mut names: Vec<&str> = headers.keys().map(|c| c.as_str()).collect();
+    names.sort();
+    assert_eq!(names, ["content-type", "set-cookie"]);
+    assert_eq!(headers.get_all("set-cookie").iter().count(), 2);
+}
+
+// Spawn `router` as a backend on a random port
+#[cfg(test)]
+pub async fn spawn_backend(router: axum::Router) -> std::net::SocketAddr {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move { axum::serve(listener, router).await.unwrap() });
+    addr
+}
+
+#[cfg(test)]
+fn test_server(addr: std::net::SocketAddr) -> SingleServer {
+    SingleServer {
+        host: addr.ip().to_string(),
+        port: addr.port(),
+        name: "echo".to_string(),
+        weight: 1,
+        pool: Default::default(),
+        timeouts: Default::default(),
+        id: 0,
+        state: Default::default(),
+    }
+}
+
+// Request bodies are streamed to the backend and responses streamed back
+#[tokio::test]
+async fn test_streaming_bodies() {
+    use axum::routing::{get, post};
+    use http_body_util::BodyExt;
+
+    const MB: usize = 1 << 20;
+    // the echo backend reads the whole body before it answers, like a write API would
+    let backend = spawn_backend(axum::Router::new()
+        .route("/echo", post(|body: axum::body::Bytes| async move { body }))
+        .layer(axum::extract::DefaultBodyLimit::disable())
+        .route("/big", get(|| async { vec![b'n'; 4 * MB] }))
+        .route("/stream", get(|| async {
+            let chunks = (0..64).map(|_| Ok::<_, std::io::Error>(vec![b's'; 64 * 1024]));
+            Body::from_stream(futures_util::stream::iter(chunks))
+        }))).await;
+    let server = test_server(backend);
+    let client = build_client(&PoolConf::default(), None);
+
+    let proxy = |method: &str, path: &str, body: Body, limits: LimitsConf| {
+        let req = Request::builder().method(method).uri(path).body(body).unwrap();
+        let (client, server) = (client.clone(), server.clone());
+        async move {
+            let response = handle_request(&client, &server, &limits, &ForwardingConf::default(), &TimeoutConf::default(), req).await;
+            let status = response.status();
+            (status, response.into_body().collect().await.map(|c| c.to_bytes().len()))
+        }
+    };
+    let chunked = |chunks: usize| Body::from_stream(futures_util::stream::iter(
+        (0..chunks).map(|_| Ok::<_, std::io::Error>(vec![b'p'; 64 * 1024]))));
+    let limits = LimitsConf { max_request_body: Some(MB), max_response_body: Some(MB) };
+
+    let (status, len) = proxy("POST", "/echo", Body::from("neo feed"), LimitsConf::default()).await;
+    assert_eq!((status, len.unwrap()), (StatusCode::OK, 8));
+    let (status, len) = proxy("POST", "/echo", chunked(128), LimitsConf::default()).await;
+    assert_eq!((status, len.unwrap()), (StatusCode::OK, 8 * MB));
+
+    // requests over the limit, by their length or while streaming
+    let (status, _) = proxy("POST", "/echo", Body::from(vec![b'p'; 2 * MB]), limits).await;
+    assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+    let (status, _) = proxy("POST", "/echo", chunked(32), limits).await;
+    assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+    let (status, len) = proxy("POST", "/echo", chunked(8), limits).await;
+    assert_eq!((status, len.unwrap()), (StatusCode::OK, MB / 2));
+
+    // responses over the limit, by their length or while streaming
+    let (status, len) = proxy("GET", "/big", Body::empty(), LimitsConf::default()).await;
+    assert_eq!((status, len.unwrap()), (StatusCode::OK, 4 * MB));
+    let (status, _) = proxy("GET", "/big", Body::empty(), limits).await;
+    assert_eq!(status, StatusCode::BAD_GATEWAY);
+    let (status, len) = proxy("GET", "/stream", Body::empty(), limits).await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(len.is_err());
+}
+
+// A backend that refuses connections costs as much as a slow one
+#[tokio::test]
+async fn test_failures_cost_latency() {
+    let dead = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+    let server = test_server(dead);
+    let client = build_client(&PoolConf::default(), None);
+    let req = Request::get("/neo").body(Body::empty()).unwrap();
+
+    let response = handle_request(&client, &server, &LimitsConf::default(), &ForwardingConf::default(), &TimeoutConf::default(), req).await;
+    assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+    assert!(server.state.latency_ewma() > crate::server_state::FAILURE_PENALTY.as_secs_f64() * 0.9, "{}", server.state.latency_ewma());
+}
@@ -0,0 +1,246 @@
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use log::{error, info, trace, warn};
+use tokio::signal::unix::{signal, SignalKind};
+use crate::AppContext;
+use crate::balancing::Balancer;
+use crate::config::{AppConf, AppConfig, SingleServer};
+
+/// How often the config file is checked for modifications
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Difference between two lists of declared servers, matched by name
+#[derive(Debug, Default)]
+pub struct ServerDiff {
+    /// Servers present only in the new config
+    pub added: Vec<SingleServer>,
+    /// Names of servers present only in the old config
+    pub removed: Vec<String>,
+    /// Servers whose host, port, weight, pool settings or timeouts changed
+    pub changed: Vec<SingleServer>,
+}
+
+impl ServerDiff {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+}
+
+pub fn diff_servers(old: &[SingleServer], new: &[SingleServer]) -> ServerDiff {
+    let mut diff = ServerDiff::default();
+
+    for server in new {
+        match old.iter().find(|c| c.name == server.name) {
+            None => diff.added.push(server.clone()),
+            Some(prev) => {
+                if prev.host != server.host || prev.port != server.port || prev.weight != server.weight || prev.pool != server.pool || prev.timeouts != server.timeouts {
+                    diff.changed.push(server.clone());
+                }
+            }
+        }
+    }
+    for server in old {
+        if !new.iter().any(|c| c.name == server.name) {
+            diff.removed.push(server.name.clone());
+        }
+    }
+    diff
+}
+
+/// Sections of the config that changed but are only read when klein starts
+fn restart_required(old: &AppConfig, new: &AppConf) -> Vec<&'static str> {
+    [
+        ("affinity", old.affinity != new.affinity),
+        ("limits", old.limits != new.limits),
+        ("forwarding", old.forwarding != new.forwarding),
+        ("retries", old.retries != new.retries),
+        ("circuit_breaker", old.circuit_breaker != new.circuit_breaker),
+        ("health_check", old.health_check != new.health_check),
+        ("outlier_detection", old.outlier_detection != new.outlier_detection),
+        ("replicas", old.replicas != new.replicas),
+    ].into_iter().filter(|(_, changed)| *changed).map(|(name, _)| name).collect()
+}
+
+/// Apply a freshly read config to the running balancer
+///
+/// Declared servers are diffed against the previously declared ones and applied
+/// to the server pool under a single write lock, servers added through `/add` are kept.
+/// Requests already in flight hold their own copy of the server they were routed to
+/// so they finish against the old backend. New global pool settings and timeouts take
+/// effect on the next request, which gets a client built with them. Sections that are
+/// only read on startup are logged as needing a restart.
+pub fn apply_config(ctx: &AppContext, conf: AppConf) {
+    if conf.host != ctx.app_config.host || conf.port != ctx.app_config.port {
+        warn!("Listener changed to {}:{} but klein is bound to {}:{}, restart to apply it",
+            conf.host, conf.port, ctx.app_config.host, ctx.app_config.port);
+    }
+    let sections = restart_required(&ctx.app_config, &conf);
+    if !sections.is_empty() {
+        warn!("Changes to [{}] are not applied to a running klein, restart to apply them", sections.join("], ["));
+    }
+    let mut pool_conf = ctx.app_config.pool.write().unwrap();
+    if *pool_conf != conf.pool {
+        info!("Pool settings changed to {:?}", conf.pool);
+        *pool_conf = conf.pool;
+    }
+    drop(pool_conf);
+    let mut timeouts = ctx.app_config.timeouts.write().unwrap();
+    if *timeouts != conf.timeouts {
+        info!("Timeouts changed to {:?}", conf.timeouts);
+        *timeouts = conf.timeouts;
+    }
+    drop(timeouts);
+
+    let mut balancer = ctx.balancer.write().unwrap();
+    if !balancer.is_built_from(&conf.balancing, &conf.routes) {
+        info!("Balancing changed to {:?}, routes: {:?}", conf.balancing.strategy, conf.routes);
+        *balancer = Balancer::new(&conf.balancing, &conf.routes);
+    }
+    drop(balancer);
+
+    let new_servers: Vec<SingleServer> = conf.servers.into_values().collect();
+
+    let mut declared = ctx.app_config.servers.write().unwrap();
+    let diff = diff_servers(&declared, &new_servers);
+    let mut pool = ctx.hash_server.write().unwrap();
+    let ring_changed = pool.ring() != &conf.ring;
+
+    if diff.is_empty() && !ring_changed {
+        trace!("Config reloaded, no server changes");
+        return;
+    }
+    if ring_changed {
+        info!("Ring parameters changed to {:?}, rebuilding the ring", conf.ring);
+    }
+    info!("Config reloaded, added: {:?}, removed: {:?}, changed: {:?}",
+        diff.added.iter().map(|c| &c.name).collect::<Vec<_>>(),
+        diff.removed,
+        diff.changed.iter().map(|c| &c.name).collect::<Vec<_>>());
+
+    if ring_changed {
+        // every slot moves anyway, keep everything that is not affected by the diff, with its id
+        let mut servers: Vec<SingleServer> = pool.server_containers()
+            .into_iter()
+            .filter(|c| !diff.removed.contains(&c.name) && !diff.changed.iter().any(|s| s.name == c.name))
+            .collect();
+        servers.extend(diff.added.iter().cloned());
+        servers.extend(diff.changed.iter().cloned());
+
+        *pool = pool.with_servers(conf.ring, servers);
+    } else {
+        // only touch the virtual servers of servers in the diff
+        let mut added: Vec<&SingleServer> = diff.added.iter().collect();
+        for name in &diff.removed {
+            pool.remove_server(name);
+        }
+        for server in &diff.changed {
+            let Some(prev) = declared.iter().find(|c| c.name == server.name) else { continue };
+            if prev.host != server.host || prev.port != server.port {
+                // a new address is a different backend, so the server starts over with new state
+                pool.remove_server(&server.name);
+                added.push(server);
+            } else if prev.pool != server.pool || prev.timeouts != server.timeouts {
+                // the client is built again with the new settings on the next request
+                if let Some(removed) = pool.remove_server(&server.name) {
+                    pool.insert_server(SingleServer { state: removed.state, ..server.clone() });
+                }
+            } else {
+                pool.set_weight(&server.name, server.weight);
+            }
+        }
+        for server in added {
+            pool.insert_server(SingleServer { state: Default::default(), ..server.clone() });
+        }
+    }
+    *declared = new_servers;
+}
+
+fn modified_time(ctx: &AppContext) -> Option<SystemTime> {
+    std::fs::metadata(&ctx.app_config.loader.path).and_then(|c| c.modified()).ok()
+}
+
+fn reload(ctx: &AppContext) {
+    match ctx.app_config.loader.load() {
+        Ok(conf) => apply_config(ctx, conf),
+        Err(e) => error!("Not reloading config, keeping the current one: {}", e),
+    }
+}
+
+/// Watch the config file and reload it when it changes on disk or klein receives `SIGHUP`
+pub async fn watch_config(ctx: Arc<AppContext>) {
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(c) => Some(c),
+        Err(e) => {
+            error!("Could not install SIGHUP handler, only watching the file: {}", e);
+            None
+        }
+    };
+    let mut interval = tokio::time::interval(POLL_INTERVAL);
+    let mut last_modified = modified_time(&ctx);
+
+    loop {
+        tokio::select! {
+            Some(_) = async { hangup.as_mut()?.recv().await } => {
+                info!("Received SIGHUP, reloading config");
+                reload(&ctx);
+            }
+            _ = interval.tick() => {
+                let modified = modified_time(&ctx);
+                if modified != last_modified {
+                    info!("Config file {} changed, reloading", ctx.app_config.loader.path.display());
+                    last_modified = modified;
+                    reload(&ctx);
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn test_diff_servers() {
+    let server = |name: &str, port: u16| SingleServer {
+        host: "127.0.0.1".to_string(),
+        port,
+        name: name.to_string(),
+        weight: 1,
+        pool: Default::default(),
+        timeouts: Default::default(),
+        id: 0,
+        state: Default::default(),
+    };
+    let old = vec![server("a", 8000), server("b", 8001), server("c", 8002)];
+    let new = vec![server("a", 8000), server("b", 9001), server("d", 8003)];
+
+    let diff = diff_servers(&old, &new);
+    assert_eq!(diff.added.iter().map(|c| c.name.as_str()).collect::<Vec<_>>(), ["d"]);
+    assert_eq!(diff.removed, ["c"]);
+    assert_eq!(diff.changed.iter().map(|c| c.name.as_str()).collect::<Vec<_>>(), ["b"]);
+}
+
+// Sections that are only read on startup are reported when they change
+#[test]
+fn test_restart_required() {
+    let base = "port = 5001\nhost = \"127.0.0.1\"\n";
+    let old = crate::config::test_app_config(base);
+
+    let same = crate::config::load_str(&format!("{base}[servers.a]\nhost = \"h\"\nport = 8000\nname = \"a\""), &[]).unwrap();
+    assert!(restart_required(&old, &same).is_empty());
+
+    let changed = crate::config::load_str(&format!("{base}[retries]\nmax_attempts = 3\n[health_check]\nenabled = false\n[replicas]\ncount = 2"), &[]).unwrap();
+    assert_eq!(restart_required(&old, &changed), ["retries", "health_check", "replicas"]);
+}
+
+// Global and per server pool settings and timeouts apply without resetting the server
+#[tokio::test]
+async fn test_reload_client_settings() {
+    let base = "port = 5001\nhost = \"127.0.0.1\"\n[servers.a]\nhost = \"127.0.0.1\"\nport = 8000\nname = \"a\"\n";
+    let ctx = AppContext::new(crate::config::test_app_config(base));
+    let before = ctx.hash_server.read().unwrap().servers()[0].clone();
+
+    let conf = crate::config::load_str(&format!("{base}[servers.a.pool]\nmax_idle = 4\n[timeouts]\nconnect_ms = 50"), &[]).unwrap();
+    apply_config(&ctx, conf);
+    let after = ctx.hash_server.read().unwrap().servers()[0].clone();
+    assert!(Arc::ptr_eq(&before.state, &after.state));
+    assert_eq!(after.pool.max_idle, Some(4));
+    assert_eq!(ctx.app_config.timeouts(&after).connect_ms, Some(50));
+}
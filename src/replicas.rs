@@ -0,0 +1,409 @@
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
+use log::{error, info, warn};
+use nanorand::{Rng, WyRand};
+use serde::Deserialize;
+use crate::AppContext;
+use crate::config::SingleServer;
+use crate::consistent_hashing::ServerPool;
+use crate::prometheus_stats::REPLICAS_REPLACED;
+use crate::runtime::{default_docker_socket, RuntimeKind};
+
+fn default_image() -> String {
+    "nasa_api".to_string()
+}
+
+fn default_container_port() -> u16 {
+    8000
+}
+
+fn default_host() -> String {
+    "127.0.0.1".to_string()
+}
+
+fn default_port_range() -> (u16, u16) {
+    (18000, 18999)
+}
+
+fn default_port_state_file() -> Option<PathBuf> {
+    Some(PathBuf::from("klein_ports.json"))
+}
+
+fn default_name_prefix() -> String {
+    "klein-replica".to_string()
+}
+
+fn default_interval_ms() -> u64 {
+    5000
+}
+
+fn default_startup_timeout_ms() -> u64 {
+    60000
+}
+
+/// Replicas started by `/add` and the supervisor, set in `[replicas]`
+///
+/// Every replica gets its name in `SERVER_ID`, the port it should listen on in `PORT`
+/// and the variables in `env`
+#[derive(Deserialize)]
+#[derive(Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ReplicaConf {
+    /// How replicas are started
+    #[serde(default)]
+    pub runtime: RuntimeKind,
+    /// Image of the backend service, for the docker runtimes
+    #[serde(default = "default_image")]
+    pub image: String,
+    /// Program and arguments of a local process, or the command overriding the image's
+    #[serde(default)]
+    pub command: Vec<String>,
+    #[serde(default)]
+    pub env: BTreeMap<String, String>,
+    /// Port the backend service listens on inside the container
+    #[serde(default = "default_container_port")]
+    pub container_port: u16,
+    /// Host replicas are reachable on
+    #[serde(default = "default_host")]
+    pub host: String,
+    /// Replicas are given ports from this range, both ends included
+    #[serde(default = "default_port_range")]
+    pub port_range: (u16, u16),
+    /// Where the ports given to replicas are saved, replicas still running after a restart
+    /// are added back to the pool
+    #[serde(default = "default_port_state_file")]
+    pub port_state_file: Option<PathBuf>,
+    /// Socket of the Docker Engine API
+    #[serde(default = "default_docker_socket")]
+    pub docker_socket: PathBuf,
+    /// Replace dead replicas and start new ones until `count` replicas are running
+    #[serde(default)]
+    pub supervise: bool,
+    /// Replicas to keep running, defaults to the number of replicas still running when
+    /// klein starts. `/add` and `/rm` change it
+    pub count: Option<usize>,
+    /// Replacements are named `<name_prefix>-<random suffix>`
+    #[serde(default = "default_name_prefix")]
+    pub name_prefix: String,
+    /// Time between two checks of the replica count
+    #[serde(default = "default_interval_ms")]
+    pub interval_ms: u64,
+    /// Replicas started by the supervisor that do not pass their health checks in this
+    /// time are removed and started again
+    #[serde(default = "default_startup_timeout_ms")]
+    pub startup_timeout_ms: u64,
+}
+
+impl Default for ReplicaConf {
+    fn default() -> Self {
+        ReplicaConf {
+            runtime: Default::default(),
+            image: default_image(),
+            command: vec![],
+            env: Default::default(),
+            container_port: default_container_port(),
+            host: default_host(),
+            port_range: default_port_range(),
+            port_state_file: default_port_state_file(),
+            docker_socket: default_docker_socket(),
+            supervise: false,
+            count: None,
+            name_prefix: default_name_prefix(),
+            interval_ms: default_interval_ms(),
+            startup_timeout_ms: default_startup_timeout_ms(),
+        }
+    }
+}
+
+/// What the supervisor does in one round
+#[derive(Debug, Default, PartialEq, Eq)]
+struct Plan {
+    /// Dead servers that get a replacement before they are removed
+    replace: Vec<String>,
+    /// Dead servers that are removed without a replacement, as enough others are running
+    remove: Vec<String>,
+    /// New replicas on top of the replacements
+    spawn: usize,
+}
+
+/// Work out how to get from the replicas in the pool to `desired` healthy ones
+///
+/// Servers that fail their health checks are dead. Healthy servers beyond `desired` are
+/// left alone, scaling down is up to `/rm`
+fn plan(servers: &[SingleServer], desired: usize) -> Plan {
+    let (healthy, dead): (Vec<_>, Vec<_>) = servers.iter().partition(|c| c.state.health.is_healthy());
+    let mut dead: Vec<String> = dead.into_iter().map(|c| c.name.clone()).collect();
+    let missing = desired.saturating_sub(healthy.len());
+
+    let remove = dead.split_off(missing.min(dead.len()));
+    Plan { spawn: missing - dead.len(), replace: dead, remove }
+}
+
+/// Replicas in the pool, the servers klein gave a port to
+///
+/// Servers declared in the config are never replaced or removed by the supervisor
+fn replicas(ctx: &AppContext) -> Vec<SingleServer> {
+    let assigned = ctx.ports.assigned();
+    ctx.hash_server.read().unwrap().servers().iter().filter(|c| assigned.contains_key(&c.name)).cloned().collect()
+}
+
+/// Whether `name` can name a replica, letters, digits, `-` and `_` only
+///
+/// Names end up in runtime commands and engine API paths, so nothing else is let through
+pub fn is_valid_name(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// A name that is not taken by any server in the pool
+pub fn fresh_name(pool: &ServerPool, prefix: &str) -> String {
+    let mut rng = WyRand::new();
+    loop {
+        let name = format!("{}-{:06x}", prefix, rng.generate_range(0..0x100_0000u32));
+        if pool.servers().iter().all(|c| c.name != name) {
+            return name;
+        }
+    }
+}
+
+/// Start a replica and add it to the pool, returns its name
+///
+/// It takes the weight, pool settings and timeouts of `like`, the replica it replaces.
+/// With health checks enabled it is not selected until it passes them
+async fn spawn_replica(ctx: &AppContext, like: Option<&SingleServer>) -> Option<String> {
+    let name = fresh_name(&ctx.hash_server.read().unwrap(), &ctx.app_config.replicas.name_prefix);
+    let Some(port) = ctx.ports.allocate(&name) else {
+        error!("Could not start a replica, no ports left in {:?}", ctx.app_config.replicas.port_range);
+        return None;
+    };
+    match ctx.runtime.start(&name, port).await {
+        Ok(output) if output.success => {
+            info!("Started replica {} on port {}", name, port);
+            let server = SingleServer {
+                id: 0,
+                name: name.clone(),
+                host: ctx.runtime.host().to_string(),
+                port,
+                weight: like.map_or(1, |c| c.weight),
+                pool: like.map(|c| c.pool).unwrap_or_default(),
+                timeouts: like.map(|c| c.timeouts).unwrap_or_default(),
+                state: Default::default(),
+            };
+            if ctx.app_config.health_check.enabled {
+                server.state.health.mark_starting();
+            }
+            ctx.hash_server.write().unwrap().insert_server(server);
+            return Some(name);
+        }
+        Ok(output) => error!("Could not start replica {}: {}", name, output.stderr),
+        Err(e) => error!("Could not start replica {}: {}", name, e),
+    }
+    ctx.ports.release(&name);
+    None
+}
+
+/// Take a server out of the pool and stop its replica
+///
+/// A replica that could not be stopped goes back in the pool and keeps its port, as with
+/// `/rm`, so it is not handed to another replica and the removal is tried again later
+async fn remove_replica(ctx: &AppContext, name: &str) {
+    let Some(server) = ctx.hash_server.write().unwrap().remove_server(name) else {
+        return;
+    };
+    let error = match ctx.runtime.stop(name).await {
+        Ok(output) if output.success => {
+            info!("Removed replica {}", name);
+            ctx.ports.release(name);
+            return;
+        }
+        Ok(output) => output.stderr,
+        Err(e) => e,
+    };
+    warn!("Could not stop replica {}, putting it back in the ring: {}", name, error);
+    ctx.hash_server.write().unwrap().insert_server(server);
+}
+
+/// A replica started by the supervisor that has not passed its health checks yet
+struct Starting {
+    name: String,
+    /// Dead replica it replaces, removed once this one is healthy
+    replaces: Option<String>,
+    deadline: Instant,
+}
+
+/// Finish replacements whose new replica passed its health checks and give up on the ones
+/// that did not in time, returns the replicas that are still starting
+async fn settle(ctx: &AppContext, starting: Vec<Starting>) -> Vec<Starting> {
+    let replicas = replicas(ctx);
+    let mut waiting = vec![];
+
+    for replica in starting {
+        // gone when /rm removed it, the dead one is replaced again in the next round
+        let Some(server) = replicas.iter().find(|c| c.name == replica.name) else {
+            continue;
+        };
+        if server.state.health.is_healthy() {
+            if let Some(dead) = &replica.replaces {
+                warn!("Replaced dead replica {} with {}", dead, replica.name);
+                REPLICAS_REPLACED.inc();
+                remove_replica(ctx, dead).await;
+            }
+        } else if Instant::now() >= replica.deadline {
+            error!("Replica {} did not pass its health checks within {}ms, removing it",
+                replica.name, ctx.app_config.replicas.startup_timeout_ms);
+            remove_replica(ctx, &replica.name).await;
+        } else {
+            waiting.push(replica);
+        }
+    }
+    waiting
+}
+
+/// Keep the desired number of replicas running
+///
+/// Dead replicas, as found by the health checks, are replaced by a new replica with a
+/// fresh name and port and the dead one's settings. The dead one stays in the pool until
+/// its replacement passes its health checks
+pub async fn supervise(ctx: Arc<AppContext>) {
+    let conf = &ctx.app_config.replicas;
+    let mut interval = tokio::time::interval(Duration::from_millis(conf.interval_ms));
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    let mut starting: Vec<Starting> = vec![];
+
+    loop {
+        interval.tick().await;
+        starting = settle(&ctx, starting).await;
+
+        // replicas that are starting, and the ones they replace, are already taken care of
+        let busy = |name: &str| starting.iter().any(|c| c.name == name || c.replaces.as_deref() == Some(name));
+        let settled: Vec<SingleServer> = replicas(&ctx).into_iter().filter(|c| !busy(&c.name)).collect();
+        let desired = ctx.desired_replicas.load(Ordering::Acquire);
+        let plan = plan(&settled, desired.saturating_sub(starting.len()));
+        let deadline = Instant::now() + Duration::from_millis(conf.startup_timeout_ms);
+
+        for dead in plan.replace {
+            if let Some(name) = spawn_replica(&ctx, settled.iter().find(|c| c.name == dead)).await {
+                info!("Starting replica {} to replace dead replica {}", name, dead);
+                starting.push(Starting { name, replaces: Some(dead), deadline });
+            }
+        }
+        for dead in &plan.remove {
+            warn!("Removing dead replica {}, {} replicas are running without it", dead, desired);
+            remove_replica(&ctx, dead).await;
+        }
+        for _ in 0..plan.spawn {
+            if let Some(name) = spawn_replica(&ctx, None).await {
+                starting.push(Starting { name, replaces: None, deadline });
+            }
+        }
+    }
+}
+
+#[test]
+fn test_replica_plan() {
+    let pool = crate::consistent_hashing::test_pool(Default::default(), 4);
+    let servers = pool.servers();
+    let names = |names: &[&str]| names.iter().map(|c| c.to_string()).collect::<Vec<_>>();
+    let kill = |i: usize| for _ in 0..3 {
+        servers[i].state.health.record_check(&Default::default(), false, &servers[i].name);
+    };
+
+    assert_eq!(plan(servers, 4), Plan::default());
+    assert_eq!(plan(servers, 6), Plan { spawn: 2, ..Default::default() });
+    // more running than desired is left to /rm
+    assert_eq!(plan(servers, 2), Plan::default());
+
+    kill(1);
+    kill(3);
+    assert_eq!(plan(servers, 4), Plan { replace: names(&["server-1", "server-3"]), ..Default::default() });
+    assert_eq!(plan(servers, 3), Plan { replace: names(&["server-1"]), remove: names(&["server-3"]), spawn: 0 });
+    assert_eq!(plan(servers, 5), Plan { replace: names(&["server-1", "server-3"]), spawn: 1, ..Default::default() });
+
+    let name = fresh_name(&pool, "replica");
+    assert!(name.starts_with("replica-") && name.len() == "replica-".len() + 6, "{name}");
+}
+
+#[tokio::test]
+async fn test_only_replicas_are_supervised() {
+    let listener = std::net::TcpListener::bind((std::net::Ipv4Addr::UNSPECIFIED, 0)).unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let state = std::env::temp_dir().join(format!("klein-supervised-{}.json", std::process::id()));
+    std::fs::write(&state, format!(r#"{{"replica": {port}}}"#)).unwrap();
+    let (_, ctx) = crate::spawn_klein(&format!("port = 1\nhost = \"127.0.0.1\"\n\
+        [servers.static]\nhost = \"127.0.0.1\"\nport = 1\nname = \"static\"\n\
+        [replicas]\nport_range = [{port}, {port}]\nport_state_file = \"{}\"", state.display())).await;
+    assert_eq!(ctx.desired_replicas.load(Ordering::Acquire), 1);
+
+    for server in ctx.hash_server.read().unwrap().servers() {
+        for _ in 0..3 {
+            server.state.health.record_check(&Default::default(), false, &server.name);
+        }
+    }
+    let replicas = replicas(&ctx);
+    assert_eq!(replicas.iter().map(|c| c.name.as_str()).collect::<Vec<_>>(), ["replica"]);
+    assert_eq!(plan(&replicas, 1).replace, ["replica"]);
+    drop(listener);
+    let _ = std::fs::remove_file(&state);
+}
+
+// A replacement takes the dead replica's settings and its place once it passes its health checks
+#[tokio::test]
+async fn test_replacement() {
+    let state = std::env::temp_dir().join(format!("klein-replace-{}.json", std::process::id()));
+    let (_, ctx) = crate::spawn_klein(&format!("port = 1\nhost = \"127.0.0.1\"\n\
+        [replicas]\nruntime = \"local_process\"\ncommand = [\"sleep\", \"30\"]\nport_range = [19110, 19113]\n\
+        port_state_file = \"{}\"", state.display())).await;
+    let find = |name: &str| ctx.hash_server.read().unwrap().servers().iter().find(|c| c.name == name).cloned();
+    let names = || replicas(&ctx).into_iter().map(|c| c.name).collect::<Vec<_>>();
+
+    let dead = spawn_replica(&ctx, None).await.unwrap();
+    ctx.hash_server.write().unwrap().set_weight(&dead, 3);
+    let name = spawn_replica(&ctx, find(&dead).as_ref()).await.unwrap();
+    let replacement = find(&name).unwrap();
+    assert_eq!(replacement.weight, 3);
+    assert!(!replacement.state.health.is_healthy());
+
+    // the dead replica is kept until its replacement is healthy
+    let deadline = Instant::now() + Duration::from_secs(60);
+    let starting = settle(&ctx, vec![Starting { name: name.clone(), replaces: Some(dead.clone()), deadline }]).await;
+    assert_eq!((starting.len(), names()), (1, vec![dead.clone(), name.clone()]));
+    for _ in 0..2 {
+        replacement.state.health.record_check(&Default::default(), true, &name);
+    }
+    assert!(settle(&ctx, starting).await.is_empty());
+    assert_eq!(names(), vec![name.clone()]);
+
+    // one that never gets healthy is given up on
+    let late = spawn_replica(&ctx, None).await.unwrap();
+    assert!(settle(&ctx, vec![Starting { name: late.clone(), replaces: None, deadline: Instant::now() }]).await.is_empty());
+    assert_eq!(names(), vec![name.clone()]);
+    assert!(!ctx.ports.assigned().contains_key(&late));
+
+    ctx.runtime.stop(&name).await.unwrap();
+    let _ = std::fs::remove_file(&state);
+}
+
+// A replica that cannot be stopped stays in the pool with its port
+#[tokio::test]
+async fn test_remove_replica() {
+    let state = std::env::temp_dir().join(format!("klein-remove-{}.json", std::process::id()));
+    let (_, ctx) = crate::spawn_klein(&format!("port = 1\nhost = \"127.0.0.1\"\n\
+        [replicas]\nruntime = \"local_process\"\ncommand = [\"sleep\", \"30\"]\nport_range = [19130, 19133]\n\
+        port_state_file = \"{}\"", state.display())).await;
+    let names = || replicas(&ctx).into_iter().map(|c| c.name).collect::<Vec<_>>();
+
+    // not a child of this klein, so the local process runtime cannot stop it
+    let port = ctx.ports.allocate("ghost").unwrap();
+    ctx.hash_server.write().unwrap().add_server("ghost".to_string(), "127.0.0.1".to_string(), port, 1);
+    remove_replica(&ctx, "ghost").await;
+    assert_eq!(names(), ["ghost"]);
+    assert_eq!(ctx.ports.assigned().get("ghost"), Some(&port));
+
+    let name = spawn_replica(&ctx, None).await.unwrap();
+    remove_replica(&ctx, &name).await;
+    assert_eq!(names(), ["ghost"]);
+    assert!(!ctx.ports.assigned().contains_key(&name));
+    let _ = std::fs::remove_file(&state);
+}
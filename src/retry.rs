@@ -0,0 +1,235 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use axum::body::{Body, Bytes};
+use axum::extract::Request;
+use axum::http::{request, Method, StatusCode};
+use axum::response::Response;
+use http_body_util::BodyExt;
+use log::warn;
+use nanorand::{Rng, WyRand};
+use serde::Deserialize;
+use crate::prometheus_stats::RETRY_BUDGET_EXHAUSTED;
+use crate::proxy::UpstreamFailure;
+
+/// Length of the window the retry budget is counted over
+const BUDGET_WINDOW: Duration = Duration::from_secs(10);
+
+fn default_max_attempts() -> usize {
+    2
+}
+
+fn default_retry_on() -> Vec<u16> {
+    vec![502, 503, 504]
+}
+
+fn default_backoff_base_ms() -> u64 {
+    25
+}
+
+fn default_backoff_max_ms() -> u64 {
+    250
+}
+
+fn default_budget_ratio() -> f64 {
+    0.2
+}
+
+fn default_budget_min_per_sec() -> usize {
+    10
+}
+
+fn default_max_replay_body() -> usize {
+    64 * 1024
+}
+
+/// When and how failed requests are retried on another backend, set in `[retries]`
+#[derive(Deserialize)]
+#[derive(Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct RetryConf {
+    /// Attempts per request including the first one, 1 disables retries
+    #[serde(default = "default_max_attempts")]
+    pub max_attempts: usize,
+    /// Backend statuses that are retried, besides failing to connect, resets and timeouts
+    #[serde(default = "default_retry_on")]
+    pub retry_on: Vec<u16>,
+    /// Also retry POST, PATCH and other methods that are not idempotent
+    #[serde(default)]
+    pub non_idempotent: bool,
+    /// The backoff before the nth retry is random, up to `backoff_base_ms * 2^(n-1)`
+    #[serde(default = "default_backoff_base_ms")]
+    pub backoff_base_ms: u64,
+    /// Upper bound of the backoff
+    #[serde(default = "default_backoff_max_ms")]
+    pub backoff_max_ms: u64,
+    /// Retries may add at most this fraction of requests on top
+    #[serde(default = "default_budget_ratio")]
+    pub budget_ratio: f64,
+    /// Retries per second that are always allowed, so little traffic can still be retried
+    #[serde(default = "default_budget_min_per_sec")]
+    pub budget_min_per_sec: usize,
+    /// Largest request body in bytes that is kept to be sent again, larger ones are not retried
+    #[serde(default = "default_max_replay_body")]
+    pub max_replay_body: usize,
+}
+
+impl Default for RetryConf {
+    fn default() -> Self {
+        RetryConf {
+            max_attempts: default_max_attempts(),
+            retry_on: default_retry_on(),
+            non_idempotent: false,
+            backoff_base_ms: default_backoff_base_ms(),
+            backoff_max_ms: default_backoff_max_ms(),
+            budget_ratio: default_budget_ratio(),
+            budget_min_per_sec: default_budget_min_per_sec(),
+            max_replay_body: default_max_replay_body(),
+        }
+    }
+}
+
+impl RetryConf {
+    fn may_retry(&self, method: &Method) -> bool {
+        self.max_attempts > 1 && (self.non_idempotent || method.is_idempotent())
+    }
+
+    /// Whether a response is a failure worth trying another backend for
+    pub fn should_retry(&self, response: &Response) -> bool {
+        response.extensions().get::<UpstreamFailure>().is_some() || self.retry_on.contains(&response.status().as_u16())
+    }
+
+    /// Wait before the `retry`th retry, with full jitter
+    pub async fn backoff(&self, retry: u32) {
+        let cap = self.backoff_base_ms
+            .saturating_mul(1u64 << (retry.saturating_sub(1)).min(32))
+            .min(self.backoff_max_ms);
+        if cap > 0 {
+            let delay = WyRand::new().generate_range(0..=cap);
+            tokio::time::sleep(Duration::from_millis(delay)).await;
+        }
+    }
+}
+
+/// Global limit on retries, so a failing backend cannot multiply the load on the others
+///
+/// Over a window of 10 seconds at most `budget_min_per_sec * 10 + budget_ratio * requests`
+/// retries are allowed
+pub struct RetryBudget {
+    /// Start of the window, requests and retries in it
+    window: Mutex<(Instant, usize, usize)>,
+}
+
+impl RetryBudget {
+    pub fn new() -> RetryBudget {
+        RetryBudget { window: Mutex::new((Instant::now(), 0, 0)) }
+    }
+
+    fn current(&self) -> std::sync::MutexGuard<'_, (Instant, usize, usize)> {
+        let mut window = self.window.lock().unwrap();
+        if window.0.elapsed() >= BUDGET_WINDOW {
+            *window = (Instant::now(), 0, 0);
+        }
+        window
+    }
+
+    /// Count a request, which adds to the budget
+    pub fn deposit(&self) {
+        self.current().1 += 1;
+    }
+
+    /// Take one retry from the budget, false when it is used up
+    pub fn withdraw(&self, conf: &RetryConf) -> bool {
+        let mut window = self.current();
+        let allowed = conf.budget_min_per_sec * BUDGET_WINDOW.as_secs() as usize
+            + (conf.budget_ratio * window.1 as f64) as usize;
+        if window.2 >= allowed {
+            RETRY_BUDGET_EXHAUSTED.inc();
+            return false;
+        }
+        window.2 += 1;
+        true
+    }
+}
+
+/// A request that can be sent more than once
+pub enum Replay {
+    /// Not retryable, sent as is
+    Once(Option<Request>),
+    /// Buffered, every attempt gets a copy
+    Buffered(request::Parts, Bytes),
+}
+
+impl Replay {
+    /// Buffer a request if it may be retried and its body is small enough
+    ///
+    /// Fails with `400 Bad Request` when the body cannot be read
+    pub async fn new(req: Request, conf: &RetryConf) -> Result<Replay, Response> {
+        if !conf.may_retry(req.method()) {
+            return Ok(Replay::Once(Some(req)));
+        }
+        let small_enough = axum::body::HttpBody::size_hint(req.body()).upper().is_some_and(|c| c <= conf.max_replay_body as u64);
+        if !small_enough {
+            return Ok(Replay::Once(Some(req)));
+        }
+        let (parts, body) = req.into_parts();
+        match body.collect().await {
+            Ok(body) => Ok(Replay::Buffered(parts, body.to_bytes())),
+            Err(e) => {
+                warn!("Could not read request body: {}", e);
+                Err(Response::builder().status(StatusCode::BAD_REQUEST).body(Body::from("could not read request body")).unwrap())
+            }
+        }
+    }
+
+    /// Whether another attempt can be made
+    pub fn can_retry(&self) -> bool {
+        matches!(self, Replay::Buffered(..))
+    }
+
+    /// The request for the next attempt
+    ///
+    /// # Panics
+    ///
+    /// When called again on a request that cannot be retried
+    pub fn request(&mut self) -> Request {
+        match self {
+            Replay::Once(req) => req.take().expect("request that cannot be retried was sent twice"),
+            Replay::Buffered(parts, body) => Request::from_parts(parts.clone(), Body::from(body.clone())),
+        }
+    }
+}
+
+#[test]
+fn test_retry_budget() {
+    let conf = RetryConf { budget_ratio: 0.5, budget_min_per_sec: 1, ..Default::default() };
+    let budget = RetryBudget::new();
+
+    // 10 retries are always allowed in a window
+    for _ in 0..10 {
+        assert!(budget.withdraw(&conf));
+    }
+    assert!(!budget.withdraw(&conf));
+
+    // and one more for every 2 requests
+    for _ in 0..20 {
+        budget.deposit();
+    }
+    assert_eq!((0..20).filter(|_| budget.withdraw(&conf)).count(), 10);
+}
+
+#[tokio::test]
+async fn test_replay() {
+    let conf = RetryConf { max_replay_body: 8, ..Default::default() };
+    let request = |method: &str, body: &'static str| Request::builder().method(method).uri("/neo").body(Body::from(body)).unwrap();
+
+    let mut replay = Replay::new(request("PUT", "small"), &conf).await.unwrap();
+    assert!(replay.can_retry());
+    for _ in 0..2 {
+        let body = replay.request().into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(body, "small");
+    }
+    assert!(!Replay::new(request("PUT", "larger than 8"), &conf).await.unwrap().can_retry());
+    assert!(!Replay::new(request("POST", ""), &conf).await.unwrap().can_retry());
+    assert!(Replay::new(request("POST", ""), &RetryConf { non_idempotent: true, ..conf.clone() }).await.unwrap().can_retry());
+    assert!(!Replay::new(request("GET", ""), &RetryConf { max_attempts: 1, ..conf }).await.unwrap().can_retry());
+}
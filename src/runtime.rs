@@ -0,0 +1,360 @@
+use std::collections::{BTreeMap, HashMap};
+use std::future::Future;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::process::Stdio;
+use std::sync::Mutex;
+use axum::body::{Body, Bytes};
+use axum::http::{header, Method, Request, StatusCode};
+use http_body_util::BodyExt;
+use hyper_util::rt::TokioIo;
+use log::{info, warn};
+use serde::Deserialize;
+use serde_json::json;
+use tokio::process::{Child, Command};
+use crate::replicas::ReplicaConf;
+
+/// How replicas are started, set with `runtime` in `[replicas]`
+#[derive(Deserialize)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum RuntimeKind {
+    /// `docker run` and `docker rm`
+    #[default]
+    DockerCli,
+    /// The Docker Engine API on its unix socket
+    DockerApi,
+    /// `command` as a child process of klein, told its port in `PORT`
+    LocalProcess,
+}
+
+/// Outcome of starting or stopping a replica, as reported by `/add` and `/rm`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RuntimeOutput {
+    pub success: bool,
+    /// Exit code of the command, or the HTTP status for the Docker Engine API
+    pub status: i32,
+    pub stdout: String,
+    pub stderr: String,
+}
+
+pub type RuntimeFuture<'a> = Pin<Box<dyn Future<Output=Result<RuntimeOutput, String>> + Send + 'a>>;
+
+/// Starts and stops replicas of the backend service
+///
+/// A replica named `name` serves on `port` of the host given by `host`. Errors are
+/// failures to run the runtime at all, a runtime that ran but refused is reported
+/// in the output
+pub trait ReplicaRuntime: Send + Sync {
+    fn start<'a>(&'a self, name: &'a str, port: u16) -> RuntimeFuture<'a>;
+    fn stop<'a>(&'a self, name: &'a str) -> RuntimeFuture<'a>;
+    /// Take over a replica started before klein restarted, false when this runtime cannot stop it
+    fn adopt(&self, name: &str) -> bool;
+    /// Host replicas are reachable on
+    fn host(&self) -> &str;
+}
+
+/// Runtime selected by the config
+pub fn new_runtime(conf: &ReplicaConf) -> Box<dyn ReplicaRuntime> {
+    match conf.runtime {
+        RuntimeKind::DockerCli => Box::new(DockerCli { conf: conf.clone() }),
+        RuntimeKind::DockerApi => Box::new(DockerApi { conf: conf.clone() }),
+        RuntimeKind::LocalProcess => Box::new(LocalProcess { conf: conf.clone(), children: Default::default() }),
+    }
+}
+
+/// Environment of a replica: `SERVER_ID`, the configured variables and `PORT`
+fn replica_env(conf: &ReplicaConf, name: &str, port: u16) -> BTreeMap<String, String> {
+    let mut env = BTreeMap::from([("SERVER_ID".to_string(), name.to_string())]);
+    env.extend(conf.env.iter().map(|(k, v)| (k.clone(), v.clone())));
+    env.insert("PORT".to_string(), port.to_string());
+    env
+}
+
+async fn run(command: &mut Command) -> Result<RuntimeOutput, String> {
+    let output = command.output().await.map_err(|e| e.to_string())?;
+    Ok(RuntimeOutput {
+        success: output.status.success(),
+        status: output.status.code().unwrap_or(-255),
+        stdout: String::from_utf8_lossy(&output.stdout).trim().to_string(),
+        stderr: String::from_utf8_lossy(&output.stderr).trim().to_string(),
+    })
+}
+
+/// Containers run with the `docker` command
+struct DockerCli {
+    conf: ReplicaConf,
+}
+
+impl ReplicaRuntime for DockerCli {
+    fn start<'a>(&'a self, name: &'a str, port: u16) -> RuntimeFuture<'a> {
+        let mut command = Command::new("docker");
+        command.args(["run", "-d", "--name", name, "-p", &format!("{}:{}", port, self.conf.container_port)]);
+        for (key, value) in replica_env(&self.conf, name, self.conf.container_port) {
+            command.arg("-e").arg(format!("{key}={value}"));
+        }
+        command.arg(&self.conf.image).args(&self.conf.command);
+        Box::pin(async move { run(&mut command).await })
+    }
+
+    fn stop<'a>(&'a self, name: &'a str) -> RuntimeFuture<'a> {
+        Box::pin(async move { run(Command::new("docker").args(["rm", "-f", name])).await })
+    }
+
+    // containers are stopped by name, whoever started them
+    fn adopt(&self, _name: &str) -> bool {
+        true
+    }
+
+    fn host(&self) -> &str {
+        &self.conf.host
+    }
+}
+
+/// Containers run through the Docker Engine API
+struct DockerApi {
+    conf: ReplicaConf,
+}
+
+impl DockerApi {
+    /// Send one request to the engine over a new connection to its socket
+    async fn request(&self, method: Method, path: &str, body: Option<serde_json::Value>) -> Result<(StatusCode, Bytes), String> {
+        let stream = tokio::net::UnixStream::connect(&self.conf.docker_socket).await
+            .map_err(|e| format!("could not connect to {}: {}", self.conf.docker_socket.display(), e))?;
+        let (mut sender, connection) = hyper::client::conn::http1::handshake(TokioIo::new(stream)).await
+            .map_err(|e| e.to_string())?;
+        tokio::spawn(connection);
+
+        let req = Request::builder()
+            .method(method)
+            .uri(path)
+            .header(header::HOST, "docker")
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(body.map(|c| Body::from(c.to_string())).unwrap_or_default())
+            .map_err(|e| e.to_string())?;
+        let response = sender.send_request(req).await.map_err(|e| e.to_string())?;
+        let status = response.status();
+        let body = response.into_body().collect().await.map_err(|e| e.to_string())?.to_bytes();
+        Ok((status, body))
+    }
+}
+
+/// Output of an engine API call, the error message of a failed one is in `stderr`
+fn api_output(status: StatusCode, body: &[u8]) -> RuntimeOutput {
+    let body = String::from_utf8_lossy(body).trim().to_string();
+    let (stdout, stderr) = if status.is_success() { (body, String::new()) } else { (String::new(), body) };
+    RuntimeOutput { success: status.is_success(), status: status.as_u16() as i32, stdout, stderr }
+}
+
+impl ReplicaRuntime for DockerApi {
+    fn start<'a>(&'a self, name: &'a str, port: u16) -> RuntimeFuture<'a> {
+        Box::pin(async move {
+            let exposed = format!("{}/tcp", self.conf.container_port);
+            let env: Vec<String> = replica_env(&self.conf, name, self.conf.container_port).into_iter()
+                .map(|(k, v)| format!("{k}={v}"))
+                .collect();
+            let mut spec = json!({
+                "Image": self.conf.image,
+                "Env": env,
+                "ExposedPorts": { &exposed: {} },
+                "HostConfig": { "PortBindings": { &exposed: [{ "HostPort": port.to_string() }] } },
+            });
+            if !self.conf.command.is_empty() {
+                spec["Cmd"] = json!(self.conf.command);
+            }
+            let (status, body) = self.request(Method::POST, &format!("/containers/create?name={name}"), Some(spec)).await?;
+            if !status.is_success() {
+                return Ok(api_output(status, &body));
+            }
+            match self.request(Method::POST, &format!("/containers/{name}/start"), None).await {
+                // the id of the created container, not the empty body of the start call
+                Ok((status, _)) if status.is_success() => Ok(api_output(status, &body)),
+                failed => {
+                    // the container would keep its name, and the next start with it would get 409
+                    if let Err(e) = self.stop(name).await.and_then(|c| if c.success { Ok(()) } else { Err(c.stderr) }) {
+                        warn!("Could not remove {} after it failed to start: {}", name, e);
+                    }
+                    failed.map(|(status, start_body)| api_output(status, &start_body))
+                }
+            }
+        })
+    }
+
+    fn stop<'a>(&'a self, name: &'a str) -> RuntimeFuture<'a> {
+        Box::pin(async move {
+            let (status, body) = self.request(Method::DELETE, &format!("/containers/{name}?force=true"), None).await?;
+            Ok(api_output(status, &body))
+        })
+    }
+
+    fn adopt(&self, _name: &str) -> bool {
+        true
+    }
+
+    fn host(&self) -> &str {
+        &self.conf.host
+    }
+}
+
+/// Replicas run as child processes of klein
+struct LocalProcess {
+    conf: ReplicaConf,
+    children: Mutex<HashMap<String, Child>>,
+}
+
+impl ReplicaRuntime for LocalProcess {
+    fn start<'a>(&'a self, name: &'a str, port: u16) -> RuntimeFuture<'a> {
+        Box::pin(async move {
+            let Some((program, args)) = self.conf.command.split_first() else {
+                return Err("replicas.command is empty".to_string());
+            };
+            if self.children.lock().unwrap().contains_key(name) {
+                return Ok(RuntimeOutput { success: false, status: -1, stdout: String::new(), stderr: format!("{name} is already running") });
+            }
+            let child = Command::new(program)
+                .args(args)
+                .envs(replica_env(&self.conf, name, port))
+                .stdin(Stdio::null())
+                .kill_on_drop(true)
+                .spawn()
+                .map_err(|e| format!("could not run {program}: {e}"))?;
+            let pid = child.id().unwrap_or_default();
+            info!("Started {} as process {}", name, pid);
+            self.children.lock().unwrap().insert(name.to_string(), child);
+            Ok(RuntimeOutput { success: true, status: 0, stdout: format!("pid {pid}"), stderr: String::new() })
+        })
+    }
+
+    fn stop<'a>(&'a self, name: &'a str) -> RuntimeFuture<'a> {
+        Box::pin(async move {
+            let Some(mut child) = self.children.lock().unwrap().remove(name) else {
+                return Ok(RuntimeOutput { success: false, status: -1, stdout: String::new(), stderr: format!("{name} is not running") });
+            };
+            if let Err(e) = child.kill().await {
+                warn!("Could not kill {}: {}", name, e);
+                return Err(e.to_string());
+            }
+            let status = child.wait().await.map_err(|e| e.to_string())?;
+            Ok(RuntimeOutput { success: true, status: status.code().unwrap_or(-255), stdout: String::new(), stderr: String::new() })
+        })
+    }
+
+    // processes of an earlier klein are not its children
+    fn adopt(&self, _name: &str) -> bool {
+        false
+    }
+
+    fn host(&self) -> &str {
+        &self.conf.host
+    }
+}
+
+/// Default path of the Docker Engine socket
+pub fn default_docker_socket() -> PathBuf {
+    PathBuf::from("/var/run/docker.sock")
+}
+
+// Local processes get their port and environment, and are killed when stopped
+#[tokio::test]
+async fn test_local_process() {
+    let dir = std::env::temp_dir().join(format!("klein-runtime-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let out = dir.join("env");
+    let conf = ReplicaConf {
+        runtime: RuntimeKind::LocalProcess,
+        command: vec!["sh".to_string(), "-c".to_string(), format!("echo $SERVER_ID $PORT $GREETING > {}; sleep 30", out.display())],
+        env: BTreeMap::from([("GREETING".to_string(), "hi".to_string())]),
+        ..Default::default()
+    };
+    let runtime = new_runtime(&conf);
+
+    assert!(runtime.start("local-1", 18123).await.unwrap().success);
+    assert!(!runtime.start("local-1", 18124).await.unwrap().success);
+    let mut written = String::new();
+    for _ in 0..100 {
+        written = std::fs::read_to_string(&out).unwrap_or_default();
+        if !written.is_empty() {
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+    }
+    assert_eq!(written.trim(), "local-1 18123 hi");
+
+    assert!(runtime.stop("local-1").await.unwrap().success);
+    assert!(!runtime.stop("local-1").await.unwrap().success);
+    let _ = std::fs::remove_dir_all(&dir);
+
+    let missing = ReplicaConf { command: vec!["klein-no-such-program".to_string()], ..conf };
+    assert!(new_runtime(&missing).start("local-2", 18125).await.is_err());
+}
+
+// The engine API is called to create, start and remove containers
+#[tokio::test]
+async fn test_docker_api() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let socket = std::env::temp_dir().join(format!("klein-docker-{}.sock", std::process::id()));
+    let _ = std::fs::remove_file(&socket);
+    let listener = tokio::net::UnixListener::bind(&socket).unwrap();
+    // answers every request with 201 and its request line and body, or 404 for the missing container
+    let requests = std::sync::Arc::new(Mutex::new(vec![]));
+    let seen = requests.clone();
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut received = vec![];
+            let mut buf = [0u8; 4096];
+            let request = loop {
+                let n = stream.read(&mut buf).await.unwrap();
+                received.extend_from_slice(&buf[..n]);
+                let text = String::from_utf8_lossy(&received).to_string();
+                if let Some((head, body)) = text.split_once("\r\n\r\n") {
+                    let length = head.lines()
+                        .find_map(|c| c.to_ascii_lowercase().strip_prefix("content-length:").map(|c| c.trim().parse::<usize>().unwrap()))
+                        .unwrap_or(0);
+                    if body.len() >= length {
+                        break (head.lines().next().unwrap().to_string(), body.to_string());
+                    }
+                }
+            };
+            let (status, reply) = if request.0.contains("missing") {
+                ("404 Not Found", r#"{"message":"No such container: missing"}"#)
+            } else if request.0.contains("broken/start") {
+                ("500 Internal Server Error", r#"{"message":"port is already allocated"}"#)
+            } else {
+                ("201 Created", r#"{"Id":"4f2a"}"#)
+            };
+            seen.lock().unwrap().push(request);
+            let response = format!("HTTP/1.1 {status}\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{reply}", reply.len());
+            stream.write_all(response.as_bytes()).await.unwrap();
+        }
+    });
+    let conf = ReplicaConf { runtime: RuntimeKind::DockerApi, docker_socket: socket.clone(), ..Default::default() };
+    let runtime = new_runtime(&conf);
+
+    let output = runtime.start("api-1", 18200).await.unwrap();
+    assert_eq!((output.success, output.stdout.as_str()), (true, r#"{"Id":"4f2a"}"#));
+    let output = runtime.stop("missing").await.unwrap();
+    assert_eq!((output.success, output.status), (false, 404));
+    assert!(output.stderr.contains("No such container"), "{output:?}");
+    // a container that does not start is removed again
+    let output = runtime.start("broken", 18201).await.unwrap();
+    assert_eq!((output.success, output.status), (false, 500));
+    assert!(output.stderr.contains("already allocated"), "{output:?}");
+
+    let requests = requests.lock().unwrap().clone();
+    let lines: Vec<_> = requests.iter().map(|c| c.0.as_str()).collect();
+    assert_eq!(lines, [
+        "POST /containers/create?name=api-1 HTTP/1.1",
+        "POST /containers/api-1/start HTTP/1.1",
+        "DELETE /containers/missing?force=true HTTP/1.1",
+        "POST /containers/create?name=broken HTTP/1.1",
+        "POST /containers/broken/start HTTP/1.1",
+        "DELETE /containers/broken?force=true HTTP/1.1",
+    ]);
+    let spec: serde_json::Value = serde_json::from_str(&requests[0].1).unwrap();
+    assert_eq!(spec["Image"], "nasa_api");
+    assert_eq!(spec["HostConfig"]["PortBindings"]["8000/tcp"][0]["HostPort"], "18200");
+    assert!(spec["Env"].as_array().unwrap().contains(&"SERVER_ID=api-1".into()));
+    let _ = std::fs::remove_file(&socket);
+}
@@ -0,0 +1,174 @@
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use crate::circuit_breaker::CircuitBreaker;
+use crate::heartbeat::HealthState;
+use crate::outlier::OutlierState;
+use crate::prometheus_stats::{BACKEND_IN_FLIGHT, BACKEND_LATENCY_EWMA};
+use crate::proxy::{build_client, HttpClient, PoolConf};
+
+/// Time for the weight of a latency sample in the peak EWMA to decay to 1/e
+pub const LATENCY_DECAY_TIME: Duration = Duration::from_secs(10);
+
+/// Least latency recorded for a request that got no response, so a backend that
+/// refuses connections does not look like the fastest one
+pub const FAILURE_PENALTY: Duration = Duration::from_secs(1);
+
+/// Live state of a backend
+///
+/// Every clone of a [`SingleServer`](crate::config::SingleServer) shares the same state,
+/// so the copy handed to a request updates what the server pool sees
+#[derive(Debug, Default)]
+pub struct ServerState {
+    in_flight: AtomicUsize,
+    latency: Mutex<PeakEwma>,
+    /// Client with the settings it was built with
+    client: Mutex<Option<(PoolConf, Option<Duration>, HttpClient)>>,
+    /// Takes the backend out of selection while it keeps failing
+    pub breaker: CircuitBreaker,
+    /// Whether the backend passes its health checks
+    pub health: HealthState,
+    /// Outcomes of live requests, ejects the backend when it fails them
+    pub outlier: OutlierState,
+}
+
+/// Exponentially weighted moving average of latency that jumps to peaks immediately
+///
+/// As in Finagle, a slow response is taken at face value while faster ones only pull
+/// the average down gradually, so a backend that starts struggling is avoided quickly
+#[derive(Debug)]
+struct PeakEwma {
+    /// Average latency in seconds as of `stamp`
+    value: f64,
+    stamp: Instant,
+}
+
+impl Default for PeakEwma {
+    fn default() -> Self {
+        PeakEwma { value: 0.0, stamp: Instant::now() }
+    }
+}
+
+impl PeakEwma {
+    /// Weight of the current value after `elapsed` time
+    fn decay(&self, now: Instant) -> f64 {
+        let elapsed = now.saturating_duration_since(self.stamp);
+        (-elapsed.as_secs_f64() / LATENCY_DECAY_TIME.as_secs_f64()).exp()
+    }
+
+    fn observe(&mut self, latency: Duration, now: Instant) {
+        let latency = latency.as_secs_f64();
+        if latency > self.value {
+            self.value = latency;
+        } else {
+            let decay = self.decay(now);
+            self.value = self.value * decay + latency * (1.0 - decay);
+        }
+        self.stamp = now;
+    }
+
+    /// The average decayed towards zero since the last sample, so a backend that
+    /// was slow gets tried again eventually
+    fn get(&self, now: Instant) -> f64 {
+        self.value * self.decay(now)
+    }
+}
+
+impl ServerState {
+    /// Number of requests currently being proxied to the backend
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.load(Ordering::Acquire)
+    }
+
+    /// Count a request as in flight until the returned guard is dropped
+    pub fn start_request(self: &Arc<Self>, name: &str) -> InFlight {
+        self.in_flight.fetch_add(1, Ordering::AcqRel);
+        BACKEND_IN_FLIGHT.with_label_values(&[name]).inc();
+
+        InFlight { state: self.clone(), name: name.to_string() }
+    }
+
+    /// Record how long the backend took to respond
+    pub fn observe_latency(&self, name: &str, latency: Duration) {
+        let mut ewma = self.latency.lock().unwrap();
+        ewma.observe(latency, Instant::now());
+        BACKEND_LATENCY_EWMA.with_label_values(&[name]).set(ewma.value);
+    }
+
+    /// Record a request that got no response, as taking at least [`FAILURE_PENALTY`]
+    pub fn observe_failure(&self, name: &str, elapsed: Duration) {
+        self.observe_latency(name, elapsed.max(FAILURE_PENALTY));
+    }
+
+    /// Client with the backend's connection pool, built again when the settings changed
+    ///
+    /// Requests still using the previous client keep its connections until they finish
+    pub fn client(&self, pool: &PoolConf, connect_timeout: Option<Duration>) -> HttpClient {
+        let mut client = self.client.lock().unwrap();
+        match client.as_ref() {
+            Some((p, t, c)) if p == pool && *t == connect_timeout => c.clone(),
+            _ => {
+                let built = build_client(pool, connect_timeout);
+                *client = Some((*pool, connect_timeout, built.clone()));
+                built
+            }
+        }
+    }
+
+    /// Peak EWMA of the backend's latency in seconds, 0 before the first response
+    pub fn latency_ewma(&self) -> f64 {
+        self.latency.lock().unwrap().get(Instant::now())
+    }
+}
+
+/// Guard returned by [`ServerState::start_request`]
+///
+/// Dropping it, including when the request future is cancelled, ends the request
+pub struct InFlight {
+    state: Arc<ServerState>,
+    name: String,
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.state.in_flight.fetch_sub(1, Ordering::AcqRel);
+        BACKEND_IN_FLIGHT.with_label_values(&[self.name.as_str()]).dec();
+    }
+}
+
+#[test]
+fn test_peak_ewma() {
+    let start = Instant::now();
+    let mut ewma = PeakEwma { value: 0.0, stamp: start };
+    let ms = Duration::from_millis;
+
+    // peaks are taken immediately
+    ewma.observe(ms(100), start);
+    assert_eq!(ewma.get(start), 0.1);
+    ewma.observe(ms(300), start + ms(10));
+    assert_eq!(ewma.value, 0.3);
+
+    // faster responses pull the average down by how much time passed
+    ewma.observe(ms(100), start + ms(20));
+    assert!(ewma.value > 0.29, "{}", ewma.value);
+    ewma.observe(ms(100), start + ms(20) + LATENCY_DECAY_TIME);
+    let expected = 0.1 + (0.3 - 0.1) * (-1f64).exp();
+    assert!((ewma.value - expected).abs() < 0.01, "{} != {}", ewma.value, expected);
+
+    // without samples it decays towards zero
+    let stamp = ewma.stamp;
+    assert!(ewma.get(stamp + LATENCY_DECAY_TIME * 5) < ewma.value * 0.01);
+}
+
+// The client is built again when its settings change
+#[test]
+fn test_client_settings() {
+    let state = ServerState::default();
+    let pool = PoolConf { max_idle: Some(4), idle_timeout_secs: None };
+    let settings = |state: &ServerState| state.client.lock().unwrap().as_ref().map(|(p, t, _)| (*p, *t));
+
+    state.client(&pool, None);
+    assert_eq!(settings(&state), Some((pool, None)));
+    state.client(&pool, Some(Duration::from_millis(50)));
+    assert_eq!(settings(&state), Some((pool, Some(Duration::from_millis(50)))));
+}
@@ -0,0 +1,264 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use axum::body::{Body, Bytes};
+use axum::BoxError;
+use http_body::{Frame, SizeHint};
+use log::warn;
+use serde::Deserialize;
+use tokio::time::{Instant, Sleep};
+use crate::prometheus_stats::UPSTREAM_TIMEOUTS;
+
+/// Upstream timeouts in milliseconds, set globally in `[timeouts]`, per server in
+/// `[servers.<key>.timeouts]` and per route in `[routes.timeouts]`
+///
+/// A route's timeouts take precedence over the server's, which take precedence over
+/// the global ones. Unset timeouts never expire
+#[derive(Deserialize)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct TimeoutConf {
+    /// Establishing a connection to the backend, cannot be set per route
+    /// as connections are shared by all routes
+    pub connect_ms: Option<u64>,
+    /// From sending the request until the response headers arrive
+    pub header_ms: Option<u64>,
+    /// Between two chunks of the response body
+    pub idle_ms: Option<u64>,
+    /// The whole exchange, including streaming the response body
+    pub total_ms: Option<u64>,
+}
+
+impl TimeoutConf {
+    /// These timeouts, with the ones that are not set taken from `fallback`
+    pub fn or(&self, fallback: &TimeoutConf) -> TimeoutConf {
+        TimeoutConf {
+            connect_ms: self.connect_ms.or(fallback.connect_ms),
+            header_ms: self.header_ms.or(fallback.header_ms),
+            idle_ms: self.idle_ms.or(fallback.idle_ms),
+            total_ms: self.total_ms.or(fallback.total_ms),
+        }
+    }
+
+    pub fn connect(&self) -> Option<Duration> {
+        self.connect_ms.map(Duration::from_millis)
+    }
+
+    /// Names of all timeouts that are set to 0
+    pub fn zero(&self) -> impl Iterator<Item=&'static str> + '_ {
+        [("connect_ms", self.connect_ms), ("header_ms", self.header_ms), ("idle_ms", self.idle_ms), ("total_ms", self.total_ms)]
+            .into_iter()
+            .filter(|(_, c)| *c == Some(0))
+            .map(|(name, _)| name)
+    }
+}
+
+/// Which timeout expired, used as the `timeout` label of the metric
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimeoutKind {
+    Connect,
+    Header,
+    Idle,
+    Total,
+}
+
+impl TimeoutKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TimeoutKind::Connect => "connect",
+            TimeoutKind::Header => "header",
+            TimeoutKind::Idle => "idle",
+            TimeoutKind::Total => "total",
+        }
+    }
+
+    /// Count an expired timeout for a backend
+    pub fn record(self, server_name: &str) {
+        warn!("Request to {} timed out ({})", server_name, self.as_str());
+        UPSTREAM_TIMEOUTS.with_label_values(&[server_name, self.as_str()]).inc();
+    }
+}
+
+/// Deadlines of a single request, started when the request is sent
+pub struct Deadlines {
+    header: Option<Duration>,
+    idle: Option<Duration>,
+    total: Option<Instant>,
+}
+
+impl Deadlines {
+    pub fn start(conf: &TimeoutConf) -> Deadlines {
+        Deadlines {
+            header: conf.header_ms.map(Duration::from_millis),
+            idle: conf.idle_ms.map(Duration::from_millis),
+            total: conf.total_ms.map(|c| Instant::now() + Duration::from_millis(c)),
+        }
+    }
+
+    /// The earlier of `now + timeout` and the total deadline
+    fn earliest(&self, timeout: Option<Duration>, kind: TimeoutKind) -> Option<(Instant, TimeoutKind)> {
+        let relative = timeout.map(|c| (Instant::now() + c, kind));
+        let total = self.total.map(|c| (c, TimeoutKind::Total));
+        match (relative, total) {
+            (Some(a), Some(b)) => Some(if b.0 < a.0 { b } else { a }),
+            (a, b) => a.or(b),
+        }
+    }
+
+    /// Wait for the response headers, or the timeout that expires first
+    pub async fn headers<F: Future>(&self, response: F) -> Result<F::Output, TimeoutKind> {
+        match self.earliest(self.header, TimeoutKind::Header) {
+            Some((deadline, kind)) => tokio::time::timeout_at(deadline, response).await.map_err(|_| kind),
+            None => Ok(response.await),
+        }
+    }
+
+    /// Wrap a response body so it fails once the idle or total timeout expires
+    pub fn body(self, body: Body, server_name: &str) -> Body {
+        if self.idle.is_none() && self.total.is_none() {
+            return body;
+        }
+        let timer = self.earliest(self.idle, TimeoutKind::Idle)
+            .map(|(deadline, kind)| (Box::pin(tokio::time::sleep_until(deadline)), kind));
+        Body::new(TimeoutBody { inner: body, deadlines: self, timer, server_name: server_name.to_string(), done: false })
+    }
+}
+
+/// Error of a response body that was cut off by a timeout
+#[derive(Debug)]
+pub struct TimeoutError(TimeoutKind);
+
+impl std::fmt::Display for TimeoutError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} timeout expired while streaming the response", self.0.as_str())
+    }
+}
+
+impl std::error::Error for TimeoutError {}
+
+/// Response body that fails when the backend stalls or takes too long in total
+struct TimeoutBody {
+    inner: Body,
+    deadlines: Deadlines,
+    timer: Option<(Pin<Box<Sleep>>, TimeoutKind)>,
+    server_name: String,
+    done: bool,
+}
+
+impl http_body::Body for TimeoutBody {
+    type Data = Bytes;
+    type Error = BoxError;
+
+    fn poll_frame(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Frame<Bytes>, BoxError>>> {
+        let this = self.get_mut();
+        if this.done {
+            return Poll::Ready(None);
+        }
+        if let Poll::Ready(frame) = Pin::new(&mut this.inner).poll_frame(cx) {
+            // data arrived, the idle timeout starts over
+            if let (Some(idle), Some((timer, kind))) = (this.deadlines.idle, this.timer.as_mut()) {
+                let (deadline, next) = this.deadlines.earliest(Some(idle), TimeoutKind::Idle).unwrap();
+                timer.as_mut().reset(deadline);
+                *kind = next;
+            }
+            return Poll::Ready(frame.map(|c| c.map_err(Into::into)));
+        }
+        if let Some((timer, kind)) = this.timer.as_mut() {
+            if timer.as_mut().poll(cx).is_ready() {
+                this.done = true;
+                kind.record(&this.server_name);
+                return Poll::Ready(Some(Err(TimeoutError(*kind).into())));
+            }
+        }
+        Poll::Pending
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.done || self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+#[test]
+fn test_timeout_precedence() {
+    let global = TimeoutConf { connect_ms: Some(1000), header_ms: Some(5000), idle_ms: None, total_ms: Some(30_000) };
+    let server = TimeoutConf { header_ms: Some(2000), idle_ms: Some(500), ..Default::default() };
+    let route = TimeoutConf { total_ms: Some(60_000), ..Default::default() };
+
+    let effective = route.or(&server).or(&global);
+    assert_eq!(effective, TimeoutConf { connect_ms: Some(1000), header_ms: Some(2000), idle_ms: Some(500), total_ms: Some(60_000) });
+    assert_eq!(TimeoutConf { idle_ms: Some(0), ..global }.zero().collect::<Vec<_>>(), ["idle_ms"]);
+}
+
+// Hung backends are cut off by the timeout that expires first
+#[tokio::test]
+async fn test_upstream_timeouts() {
+    use axum::http::StatusCode;
+    use axum::routing::get;
+    use http_body_util::BodyExt;
+
+    // responds after a second
+    async fn hung() -> &'static str {
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        "late"
+    }
+    // sends `chunks` chunks, `interval` apart
+    fn trickle(chunks: usize, interval: u64) -> Body {
+        Body::from_stream(futures_util::stream::unfold(0, move |i| async move {
+            if i == chunks {
+                return None;
+            }
+            tokio::time::sleep(Duration::from_millis(interval)).await;
+            Some((Ok::<_, std::io::Error>(Bytes::from_static(b"chunk")), i + 1))
+        }))
+    }
+    let backend = crate::proxy::spawn_backend(axum::Router::new()
+        .route("/hung", get(hung))
+        .route("/stall", get(|| async { trickle(2, 400) }))
+        .route("/slow", get(|| async { trickle(10, 50) }))).await;
+    let server = crate::config::SingleServer {
+        host: backend.ip().to_string(),
+        port: backend.port(),
+        name: "timeouts-test".to_string(),
+        weight: 1,
+        pool: Default::default(),
+        timeouts: Default::default(),
+        id: 0,
+        state: Default::default(),
+    };
+    let client = crate::proxy::build_client(&Default::default(), None);
+
+    let proxy = |path: &str, timeouts: TimeoutConf| {
+        let req = axum::extract::Request::get(path).body(Body::empty()).unwrap();
+        let (client, server) = (client.clone(), server.clone());
+        async move {
+            let response = crate::proxy::handle_request(&client, &server, &Default::default(), &Default::default(), &timeouts, req).await;
+            (response.status(), response.into_body().collect().await.map(|c| c.to_bytes().len()))
+        }
+    };
+    let timeouts = |header_ms, idle_ms, total_ms| TimeoutConf { connect_ms: None, header_ms, idle_ms, total_ms };
+    let count = |kind: &str| UPSTREAM_TIMEOUTS.with_label_values(&["timeouts-test", kind]).get();
+
+    let start = std::time::Instant::now();
+    assert_eq!(proxy("/hung", timeouts(Some(100), None, None)).await.0, StatusCode::GATEWAY_TIMEOUT);
+    assert_eq!(proxy("/hung", timeouts(Some(500), None, Some(100))).await.0, StatusCode::GATEWAY_TIMEOUT);
+    assert!(start.elapsed() < Duration::from_millis(800));
+    assert_eq!((count("header"), count("total")), (1.0, 1.0));
+
+    // headers arrive in time but the body stalls, or takes too long overall
+    let (status, body) = proxy("/stall", timeouts(None, Some(200), None)).await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(body.is_err());
+    let (status, body) = proxy("/slow", timeouts(None, Some(200), Some(300))).await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(body.is_err());
+    assert_eq!((count("idle"), count("total")), (1.0, 2.0));
+
+    // a slow but steady body is fine
+    let (status, body) = proxy("/slow", timeouts(Some(200), Some(200), Some(2000))).await;
+    assert_eq!((status, body.unwrap()), (StatusCode::OK, 50));
+}